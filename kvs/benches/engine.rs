@@ -0,0 +1,31 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::{KvStore, KvStoreOptions, KvsEngine};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use tempfile::TempDir;
+
+const KEYS: u32 = 10_000;
+
+// the cache is disabled, so every get reads from a file. most
+// of the values end up in immutable files, which are mapped
+fn random_get(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let options = KvStoreOptions::default()
+        .cache_capacity(0)
+        .logger(slog::Logger::root(slog::Discard, slog::o!()));
+    let kv = KvStore::open_with(dir.path(), options).unwrap();
+    for i in 0..KEYS {
+        kv.set(format!("key{}", i), "value".repeat(20)).unwrap();
+    }
+    let mut rng = SmallRng::from_seed([0; 16]);
+    c.bench_function("kvs random get", move |b| {
+        let _dir = &dir;
+        b.iter(|| {
+            let key = format!("key{}", rng.gen_range(0, KEYS));
+            kv.get(key).unwrap().unwrap();
+        })
+    });
+}
+
+criterion_group!(benches, random_get);
+criterion_main!(benches);
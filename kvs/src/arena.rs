@@ -0,0 +1,151 @@
+// Key arena
+//
+// The keys of the index are copied into chunks of a few KiB
+// rather than getting an allocation each. That saves what the
+// allocator needs per allocation and the rounding of small ones
+// up to its minimum size, which is more than most keys take.
+//
+// A key refers to its chunk, so a chunk is freed once the last
+// of its keys is dropped. The index only drops a key once no
+// reader can see it anymore, so it never points into freed
+// memory. A chunk that still has one key in use is kept as a
+// whole, though, so the stats report the memory of the chunks
+// rather than the length of the keys.
+//
+// Only the writer adds keys. The bytes of a key are written
+// before the key is put into the index and never change, so
+// readers only ever see bytes that were written before.
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::ops::Deref;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+
+const CHUNK_SIZE: usize = 4 * 1024;
+// bigger keys get a chunk of their own, so they don't leave
+// much of the current one unused
+const MAX_SHARED_KEY: usize = CHUNK_SIZE / 8;
+
+struct Chunk {
+    data: *mut u8,
+    size: usize,
+    // the bytes of all chunks that are allocated
+    allocated: Arc<AtomicUsize>,
+}
+
+// the bytes are only written through the arena and only
+// where no key can read them yet
+unsafe impl Send for Chunk {}
+unsafe impl Sync for Chunk {}
+
+impl Chunk {
+    fn new(size: usize, allocated: &Arc<AtomicUsize>) -> Chunk {
+        allocated.fetch_add(size, atomic::Ordering::Relaxed);
+        let data: Box<[u8]> = vec![0; size].into_boxed_slice();
+        Chunk {
+            data: Box::into_raw(data) as *mut u8,
+            size,
+            allocated: allocated.clone(),
+        }
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        self.allocated
+            .fetch_sub(self.size, atomic::Ordering::Relaxed);
+        unsafe {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                self.data, self.size,
+            )));
+        }
+    }
+}
+
+// a key in the index. it's no bigger than a boxed slice
+pub struct ArenaKey {
+    chunk: Arc<Chunk>,
+    offset: u32,
+    len: u32,
+}
+
+impl Deref for ArenaKey {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self.chunk.data.add(self.offset as usize), self.len as usize)
+        }
+    }
+}
+
+impl Borrow<[u8]> for ArenaKey {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for ArenaKey {
+    fn eq(&self, other: &ArenaKey) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for ArenaKey {}
+
+impl PartialOrd for ArenaKey {
+    fn partial_cmp(&self, other: &ArenaKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ArenaKey {
+    fn cmp(&self, other: &ArenaKey) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+#[derive(Default)]
+pub struct KeyArena {
+    current: Option<Arc<Chunk>>,
+    used: usize,
+    allocated: Arc<AtomicUsize>,
+}
+
+impl KeyArena {
+    pub fn alloc(&mut self, key: &[u8]) -> ArenaKey {
+        if key.len() > MAX_SHARED_KEY {
+            let chunk = Arc::new(Chunk::new(key.len(), &self.allocated));
+            return KeyArena::write(chunk, 0, key);
+        }
+        let chunk = match &self.current {
+            Some(chunk) if self.used + key.len() <= chunk.size => chunk.clone(),
+            _ => {
+                let chunk = Arc::new(Chunk::new(CHUNK_SIZE, &self.allocated));
+                self.current = Some(chunk.clone());
+                self.used = 0;
+                chunk
+            }
+        };
+        let offset = self.used;
+        self.used += key.len();
+        KeyArena::write(chunk, offset, key)
+    }
+
+    fn write(chunk: Arc<Chunk>, offset: usize, key: &[u8]) -> ArenaKey {
+        unsafe {
+            ptr::copy_nonoverlapping(key.as_ptr(), chunk.data.add(offset), key.len());
+        }
+        ArenaKey {
+            chunk,
+            offset: offset as u32,
+            len: key.len() as u32,
+        }
+    }
+
+    // the bytes of the chunks that are still in use
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(atomic::Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,55 @@
+//! Changes that are applied together
+
+/// A group of changes that are applied atomically
+///
+/// Either all of them survive a crash or none does. The
+/// changes are applied in the order they were added, so
+/// a later change to a key replaces an earlier one.
+///
+/// # Examples
+///
+/// ```
+///  # use kvs::WriteBatch;
+///  let mut batch = WriteBatch::new();
+///  batch.put(b"foo".to_vec(), b"bar".to_vec());
+///  batch.delete(b"baz".to_vec());
+///  assert_eq!(2, batch.len());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum BatchOp {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Sets the value of a key
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Put { key, value });
+    }
+
+    /// Removes a key. Unlike `KvsEngine::remove`, this is not an
+    /// error if the key does not exist.
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Delete { key });
+    }
+
+    /// The number of changes in this batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether there are no changes in this batch
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
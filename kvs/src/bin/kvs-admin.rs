@@ -0,0 +1,405 @@
+extern crate kvs;
+
+#[macro_use]
+extern crate slog;
+
+use failure::Fail;
+use kvs::encryption::EncryptionKey;
+use kvs::engine::{KvError, KvsEngine};
+use kvs::options::KvStoreOptions;
+use kvs::sled_engine::SledKvsEngine;
+use kvs::store::{KvStore, KvStoreScan, KvStoreSnapshot};
+use serde::{Deserialize, Serialize};
+use slog::{Discard, Logger};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+
+// progress is reported after every so many entries
+const PROGRESS_INTERVAL: u64 = 10_000;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    match Cmd::from_args() {
+        Cmd::Export {
+            engine,
+            format,
+            dir,
+            output,
+            key_file,
+        } => {
+            let out: Box<dyn Write> = match output {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(io::stdout()),
+            };
+            let mut out = BufWriter::new(out);
+            let count = match engine.unwrap_or(Engine::Kvs) {
+                Engine::Kvs => {
+                    let store = open_kvs(&dir, key_file.as_deref(), true)?;
+                    export(&store.snapshot(), format, &mut out)
+                }
+                Engine::Sled => export(
+                    &SledKvsEngine::open(&dir).map_err(Fail::compat)?,
+                    format,
+                    &mut out,
+                ),
+            }?;
+            out.flush()?;
+            eprintln!("Exported {} entries", count);
+        }
+        Cmd::Import {
+            engine,
+            format,
+            dir,
+            input,
+            key_file,
+        } => {
+            let input: Box<dyn io::Read> = match input {
+                Some(path) => Box::new(File::open(path)?),
+                None => Box::new(io::stdin()),
+            };
+            let mut input = BufReader::new(input);
+            let (count, expired) = match engine.unwrap_or(Engine::Kvs) {
+                Engine::Kvs => import(
+                    &open_kvs(&dir, key_file.as_deref(), false)?,
+                    format,
+                    &mut input,
+                ),
+                Engine::Sled => import(
+                    &SledKvsEngine::open(&dir).map_err(Fail::compat)?,
+                    format,
+                    &mut input,
+                ),
+            }?;
+            eprintln!(
+                "Imported {} entries, skipped {} that have expired",
+                count, expired
+            );
+        }
+        Cmd::GenerateKey { output } => {
+            let key = EncryptionKey::generate().to_hex();
+            match output {
+                Some(path) => std::fs::write(path, key + "\n")?,
+                None => println!("{}", key),
+            }
+        }
+    }
+    Ok(())
+}
+
+// the store's log would only get in the way of our output
+fn open_kvs(
+    dir: &Path,
+    key_file: Option<&Path>,
+    read_only: bool,
+) -> Result<KvStore, Box<dyn Error>> {
+    let mut options = KvStoreOptions::default()
+        .read_only(read_only)
+        .logger(Logger::root(Discard, o!()));
+    if let Some(path) = key_file {
+        options = options.encryption_key(EncryptionKey::from_file(path).map_err(Fail::compat)?);
+    }
+    Ok(KvStore::open_with(dir, options).map_err(Fail::compat)?)
+}
+
+// one key along with everything we know about it
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    key: Data,
+    value: Data,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    // milliseconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+// keys and values are written as text if they are valid
+// UTF-8, so the output can be read, and as bytes otherwise
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Data {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(bytes: Vec<u8>) -> Data {
+        String::from_utf8(bytes)
+            .map(Data::Text)
+            .unwrap_or_else(|err| Data::Bytes(err.into_bytes()))
+    }
+}
+
+impl From<Data> for Vec<u8> {
+    fn from(data: Data) -> Vec<u8> {
+        match data {
+            Data::Text(text) => text.into_bytes(),
+            Data::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+// where the entries of an export come from
+trait Source {
+    type Scan: Iterator<Item = kvs::Result<(Vec<u8>, Vec<u8>)>>;
+    fn scan(&self) -> kvs::Result<Self::Scan>;
+    fn version(&self, key: Vec<u8>) -> kvs::Result<Option<u64>>;
+    fn expires_at(&self, key: Vec<u8>) -> kvs::Result<Option<SystemTime>>;
+}
+
+// a snapshot doesn't change while we read it, so the
+// version and expiry always belong to the value
+impl Source for KvStoreSnapshot {
+    type Scan = KvStoreScan;
+    fn scan(&self) -> kvs::Result<KvStoreScan> {
+        Ok(KvStoreSnapshot::scan(self, .., None))
+    }
+    fn version(&self, key: Vec<u8>) -> kvs::Result<Option<u64>> {
+        Ok(KvStoreSnapshot::version(self, key))
+    }
+    fn expires_at(&self, key: Vec<u8>) -> kvs::Result<Option<SystemTime>> {
+        KvStoreSnapshot::expires_at(self, key)
+    }
+}
+
+// the store must not be used by anybody else in the
+// meantime, because the version is read separately
+impl Source for SledKvsEngine {
+    type Scan = <SledKvsEngine as KvsEngine>::Scan;
+    fn scan(&self) -> kvs::Result<Self::Scan> {
+        KvsEngine::scan(self, .., None)
+    }
+    fn version(&self, key: Vec<u8>) -> kvs::Result<Option<u64>> {
+        Ok(self.get_with_version(key)?.map(|(_, version)| version))
+    }
+    // sled has no ttls
+    fn expires_at(&self, _key: Vec<u8>) -> kvs::Result<Option<SystemTime>> {
+        Ok(None)
+    }
+}
+
+fn export<S: Source>(
+    source: &S,
+    format: Format,
+    out: &mut dyn Write,
+) -> Result<u64, Box<dyn Error>> {
+    let mut count = 0;
+    for pair in source.scan().map_err(Fail::compat)? {
+        let (key, value) = pair.map_err(Fail::compat)?;
+        let version = source.version(key.clone()).map_err(Fail::compat)?;
+        let expires_at = match source.expires_at(key.clone()) {
+            Ok(at) => at.map(millis_since_epoch),
+            // expired since we've read it
+            Err(KvError::KeyNotFound) => continue,
+            Err(err) => return Err(Box::new(err.compat())),
+        };
+        let entry = Entry {
+            key: key.into(),
+            value: value.into(),
+            version,
+            expires_at,
+        };
+        write_entry(out, format, &entry)?;
+        count += 1;
+        if count % PROGRESS_INTERVAL == 0 {
+            eprintln!("Exported {} entries so far", count);
+        }
+    }
+    Ok(count)
+}
+
+// returns the number of imported entries and
+// the number of those that had expired already
+fn import<E: KvsEngine>(
+    engine: &E,
+    format: Format,
+    input: &mut dyn BufRead,
+) -> Result<(u64, u64), Box<dyn Error>> {
+    let (mut count, mut expired) = (0, 0);
+    let ttls = !matches!(engine.ttl(Vec::new()), Err(KvError::Unsupported(_)));
+    // each entry is written as soon as it is read, so a dump never
+    // has to fit into memory. if an entry can't be imported, the
+    // ones before it stay imported
+    while let Some(entry) = read_entry(input, format)? {
+        if entry
+            .expires_at
+            .map(|at| at <= now_millis())
+            .unwrap_or(false)
+        {
+            expired += 1;
+            continue;
+        }
+        if entry.expires_at.is_some() && !ttls {
+            return Err(format!(
+                "The engine does not support ttls, but the entry after the {} imported ones has one",
+                count
+            )
+            .into());
+        }
+        let expires_at = entry
+            .expires_at
+            .map(|at| UNIX_EPOCH + Duration::from_millis(at));
+        engine
+            .restore(
+                entry.key.into(),
+                entry.value.into(),
+                entry.version,
+                expires_at,
+            )
+            .map_err(Fail::compat)?;
+        count += 1;
+        if count % PROGRESS_INTERVAL == 0 {
+            eprintln!("Imported {} entries so far", count);
+        }
+    }
+    engine.flush().map_err(Fail::compat)?;
+    Ok((count, expired))
+}
+
+// json and ron have one entry per line, bson
+// documents are simply written one after another
+fn write_entry(out: &mut dyn Write, format: Format, entry: &Entry) -> Result<(), Box<dyn Error>> {
+    match format {
+        Format::Jsonl => {
+            serde_json::to_writer(&mut *out, entry)?;
+            out.write_all(b"\n")?;
+        }
+        Format::Ron => {
+            out.write_all(ron::ser::to_string(entry)?.as_bytes())?;
+            out.write_all(b"\n")?;
+        }
+        Format::Bson => match bson::to_bson(entry)? {
+            bson::Bson::Document(doc) => bson::encode_document(out, &doc)?,
+            other => return Err(format!("Not a document: {}", other).into()),
+        },
+    }
+    Ok(())
+}
+
+fn read_entry(input: &mut dyn BufRead, format: Format) -> Result<Option<Entry>, Box<dyn Error>> {
+    match format {
+        Format::Jsonl | Format::Ron => {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if input.read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                if !line.trim().is_empty() {
+                    break;
+                }
+            }
+            let entry = match format {
+                Format::Jsonl => serde_json::from_str(&line)?,
+                _ => ron::de::from_str(&line)?,
+            };
+            Ok(Some(entry))
+        }
+        Format::Bson => {
+            if input.fill_buf()?.is_empty() {
+                return Ok(None);
+            }
+            let doc = bson::decode_document(input)?;
+            Ok(Some(bson::from_bson(bson::Bson::Document(doc))?))
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    millis_since_epoch(SystemTime::now())
+}
+
+fn millis_since_epoch(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Maintenance of the data directory of a key value store")]
+enum Cmd {
+    #[structopt(name = "export", about = "Writes all keys and values to a file")]
+    Export {
+        // The storage engine of the directory. Can be either 'kvs' or 'sled'
+        #[structopt(long)]
+        engine: Option<Engine>,
+        // Can be 'jsonl', 'ron' or 'bson'
+        #[structopt(long)]
+        format: Format,
+        // The data directory. Defaults to the current one
+        #[structopt(long, default_value = ".", parse(from_os_str))]
+        dir: PathBuf,
+        // Defaults to stdout
+        #[structopt(long, parse(from_os_str))]
+        output: Option<PathBuf>,
+        // The key the data is encrypted with, if it is
+        #[structopt(long = "key-file", parse(from_os_str))]
+        key_file: Option<PathBuf>,
+    },
+
+    #[structopt(name = "import", about = "Reads keys and values from a file")]
+    Import {
+        // The storage engine of the directory. Can be either 'kvs' or 'sled'
+        #[structopt(long)]
+        engine: Option<Engine>,
+        // Can be 'jsonl', 'ron' or 'bson'
+        #[structopt(long)]
+        format: Format,
+        // The data directory. Defaults to the current one
+        #[structopt(long, default_value = ".", parse(from_os_str))]
+        dir: PathBuf,
+        // Defaults to stdin
+        #[structopt(long, parse(from_os_str))]
+        input: Option<PathBuf>,
+        // Encrypt the data with this key
+        #[structopt(long = "key-file", parse(from_os_str))]
+        key_file: Option<PathBuf>,
+    },
+
+    #[structopt(name = "generate-key", about = "Creates a new random encryption key")]
+    GenerateKey {
+        // Defaults to stdout
+        #[structopt(long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Engine {
+    Kvs,
+    Sled,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Engine, String> {
+        match s {
+            "kvs" => Ok(Engine::Kvs),
+            "sled" => Ok(Engine::Sled),
+            other => Err(format!("Engine '{}' does not exist", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Jsonl,
+    Ron,
+    Bson,
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            "ron" => Ok(Format::Ron),
+            "bson" => Ok(Format::Bson),
+            other => Err(format!("Format '{}' does not exist", other)),
+        }
+    }
+}
@@ -7,7 +7,10 @@ mod protocol {
     tonic::include_proto!("kvs");
 }
 
-use protocol::{client::KvsClient, GetRequest, RemoveRequest, SetRequest};
+use protocol::{
+    client::KvsClient, BackupRequest, GetRequest, PersistRequest, RemoveRequest, ScanRequest,
+    SetRequest, TtlRequest,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -27,8 +30,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     match cmd {
-        Cmd::Set { key, value, addr } => {
-            let req = tonic::Request::new(SetRequest { key, value });
+        Cmd::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        } => {
+            // zero means no ttl on the wire
+            let ttl_millis = match ttl.map(|secs| secs.checked_mul(1000)) {
+                None => 0,
+                Some(Some(millis)) if millis > 0 => millis,
+                Some(Some(_)) => {
+                    eprintln!("The ttl must be at least one second");
+                    process::exit(1);
+                }
+                Some(None) => {
+                    eprintln!("The ttl is too long");
+                    process::exit(1);
+                }
+            };
+            let req = tonic::Request::new(SetRequest {
+                key,
+                value,
+                ttl_millis,
+            });
             client(addr).await?.set(req).await?;
         }
         Cmd::Get { key, addr } => {
@@ -47,6 +72,52 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 process::exit(1);
             }
         }
+        Cmd::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            addr,
+        } => {
+            let req = tonic::Request::new(ScanRequest {
+                start: start.unwrap_or_default().into_bytes(),
+                end: end.unwrap_or_default().into_bytes(),
+                prefix: prefix.unwrap_or_default().into_bytes(),
+                limit: limit.unwrap_or(0),
+            });
+            let mut pairs = client(addr).await?.scan(req).await?.into_inner();
+            while let Some(pair) = pairs.message().await? {
+                println!(
+                    "{} {}",
+                    String::from_utf8_lossy(&pair.key),
+                    String::from_utf8_lossy(&pair.value)
+                );
+            }
+        }
+        Cmd::Ttl { key, addr } => {
+            let req = tonic::Request::new(TtlRequest { key });
+            let resp = client(addr).await?.ttl(req).await?.into_inner();
+            if !resp.found {
+                eprintln!("Key not found");
+                process::exit(1);
+            } else if resp.expires {
+                println!("{}", resp.ttl_millis / 1000);
+            } else {
+                println!("Key does not expire");
+            }
+        }
+        Cmd::Persist { key, addr } => {
+            let req = tonic::Request::new(PersistRequest { key });
+            let resp = client(addr).await?.persist(req).await?;
+            if !resp.into_inner().found {
+                eprintln!("Key not found");
+                process::exit(1);
+            }
+        }
+        Cmd::Backup { path, addr } => {
+            let req = tonic::Request::new(BackupRequest { path });
+            client(addr).await?.backup(req).await?;
+        }
     };
 
     Ok(())
@@ -59,6 +130,9 @@ enum Cmd {
     Set {
         key: String,
         value: String,
+        // remove the value after this many seconds
+        #[structopt(long)]
+        ttl: Option<u64>,
         #[structopt(long)]
         addr: Option<String>,
     },
@@ -76,4 +150,44 @@ enum Cmd {
         #[structopt(long)]
         addr: Option<String>,
     },
+
+    #[structopt(name = "scan", about = "Lists keys and values in key order")]
+    Scan {
+        // the first key, inclusive
+        #[structopt(long)]
+        start: Option<String>,
+        // the last key, exclusive
+        #[structopt(long)]
+        end: Option<String>,
+        // only keys that start with this. replaces start and end
+        #[structopt(long)]
+        prefix: Option<String>,
+        #[structopt(long)]
+        limit: Option<u64>,
+        #[structopt(long)]
+        addr: Option<String>,
+    },
+
+    #[structopt(name = "ttl", about = "Prints the seconds until a key expires")]
+    Ttl {
+        key: String,
+        #[structopt(long)]
+        addr: Option<String>,
+    },
+
+    #[structopt(name = "persist", about = "Makes a key with a ttl live forever")]
+    Persist {
+        key: String,
+        #[structopt(long)]
+        addr: Option<String>,
+    },
+
+    #[structopt(name = "backup", about = "Copies the data of the server")]
+    Backup {
+        // a directory below the backup directory of the
+        // server, not on this machine
+        path: String,
+        #[structopt(long)]
+        addr: Option<String>,
+    },
 }
@@ -6,12 +6,22 @@ extern crate slog_async;
 extern crate slog_term;
 
 use crate::slog::Drain;
+use failure::Fail;
+use kvs::batch::WriteBatch;
+use kvs::compression::Compression;
+use kvs::encryption::EncryptionKey;
 use kvs::engine::{KvError, KvsEngine};
+use kvs::options::{Durability, KvStoreOptions};
+use kvs::sled_engine::SledKvsEngine;
 use kvs::store::KvStore;
 use std::fmt;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
+use tokio::sync::mpsc;
 use tonic::{transport::Server, Code, Request, Response, Status};
 
 mod protocol {
@@ -20,19 +30,28 @@ mod protocol {
 
 use protocol::{
     server::{Kvs, KvsServer},
-    GetReply, GetRequest, RemoveReply, RemoveRequest, SetReply, SetRequest, Value,
+    BackupReply, BackupRequest, BatchReply, BatchRequest, BytesValue, CompareAndRemoveReply,
+    CompareAndRemoveRequest, CompareAndSetReply, CompareAndSetRequest, GetBytesReply,
+    GetBytesRequest, GetReply, GetRequest, GetWithVersionReply, KeyValue, PersistReply,
+    PersistRequest, RemoveBytesRequest, RemoveReply, RemoveRequest, ScanRequest, SetBytesRequest,
+    SetReply, SetRequest, TtlReply, TtlRequest, Value, VersionedValue,
 };
 
-pub struct KvsServerImpl {}
+pub struct KvsServerImpl<E: KvsEngine> {
+    engine: E,
+    // where backups are written to, if anywhere
+    backup_dir: Option<PathBuf>,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Opt::from_args();
 
-    let engine = opt.engine.unwrap_or_else({ || Engine::Kvs });
+    let engine = opt.engine.unwrap_or(Engine::Kvs);
     let addr = opt
         .addr
-        .unwrap_or_else({ || "127.0.0.1:4000".to_owned() })
+        .clone()
+        .unwrap_or_else(|| "127.0.0.1:4000".to_owned())
         .parse()?;
 
     let decorator = slog_term::TermDecorator::new().build();
@@ -49,7 +68,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!(server_logger, "started at {}", addr);
     info!(server_logger, "using storage engine {}", engine);
 
-    let server = KvsServerImpl {};
+    let dir = Path::new(".");
+    match engine {
+        Engine::Kvs => {
+            let options = opt
+                .store_options(root.new(o!("component" => "engine")))
+                .map_err(|err| open_failed(&server_logger, err))?;
+            let engine =
+                KvStore::open_with(dir, options).map_err(|err| open_failed(&server_logger, err))?;
+            serve(engine, addr, opt.backup_dir).await
+        }
+        Engine::Sled => {
+            // sled would store the data in plain text
+            if opt.key_file.is_some() || !opt.old_key_files.is_empty() {
+                let err = KvError::Unsupported("encryption".to_owned());
+                return Err(open_failed(&server_logger, err));
+            }
+            let engine =
+                SledKvsEngine::open(dir).map_err(|err| open_failed(&server_logger, err))?;
+            serve(engine, addr, opt.backup_dir).await
+        }
+    }
+}
+
+async fn serve<E: KvsEngine + Sync>(
+    engine: E,
+    addr: SocketAddr,
+    backup_dir: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = KvsServerImpl { engine, backup_dir };
 
     Server::builder()
         .add_service(KvsServer::new(server))
@@ -59,24 +106,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-impl KvsServerImpl {
-    fn kverror_to_status(kve: KvError) -> Status {
-        Status::new(Code::Internal, format!("{:?}", kve))
-    }
+// e.g. if the directory belongs to the other engine
+fn open_failed(logger: &slog::Logger, err: KvError) -> Box<dyn std::error::Error> {
+    error!(logger, "cannot open storage engine: {}", err);
+    Box::new(err.compat())
+}
 
-    fn kv(&self) -> Result<KvStore, Status> {
-        let store = KvStore::open(Path::new("."));
-        Ok(store.map_err(KvsServerImpl::kverror_to_status)?)
+fn kverror_to_status(kve: KvError) -> Status {
+    match kve {
+        KvError::NotUtf8 => Status::new(Code::InvalidArgument, format!("{}", kve)),
+        KvError::VersionMismatch { .. } => {
+            Status::new(Code::FailedPrecondition, format!("{}", kve))
+        }
+        KvError::Unsupported(_) => Status::new(Code::Unimplemented, format!("{}", kve)),
+        other => Status::new(Code::Internal, format!("{:?}", other)),
     }
 }
 
 #[tonic::async_trait]
-impl Kvs for KvsServerImpl {
+impl<E: KvsEngine + Sync> Kvs for KvsServerImpl<E> {
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetReply>, Status> {
-        let mut kv = self.kv()?;
-        let mb_value = kv
+        let mb_value = self
+            .engine
             .get(request.into_inner().key)
-            .map_err(KvsServerImpl::kverror_to_status)?;
+            .map_err(kverror_to_status)?;
         match mb_value {
             Some(value) => Ok(Response::new(GetReply {
                 value: Some(Value { value }),
@@ -86,10 +139,15 @@ impl Kvs for KvsServerImpl {
     }
 
     async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetReply>, Status> {
-        let mut kv = self.kv()?;
         let req = request.into_inner();
-        kv.set(req.key, req.value)
-            .map_err(KvsServerImpl::kverror_to_status)?;
+        if req.ttl_millis == 0 {
+            self.engine.set(req.key, req.value)
+        } else {
+            let ttl = Duration::from_millis(req.ttl_millis);
+            self.engine
+                .set_with_ttl(req.key.into_bytes(), req.value.into_bytes(), ttl)
+        }
+        .map_err(kverror_to_status)?;
         Ok(Response::new(SetReply {}))
     }
 
@@ -97,12 +155,191 @@ impl Kvs for KvsServerImpl {
         &self,
         request: Request<RemoveRequest>,
     ) -> Result<Response<RemoveReply>, Status> {
-        let mut kv = self.kv()?;
-        match kv.remove(request.into_inner().key) {
+        match self.engine.remove(request.into_inner().key) {
             Ok(()) => Ok(Response::new(RemoveReply { removed: true })),
             Err(KvError::KeyNotFound) => Ok(Response::new(RemoveReply { removed: false })),
-            Err(other) => Err(KvsServerImpl::kverror_to_status(other)),
+            Err(other) => Err(kverror_to_status(other)),
+        }
+    }
+
+    async fn get_bytes(
+        &self,
+        request: Request<GetBytesRequest>,
+    ) -> Result<Response<GetBytesReply>, Status> {
+        let mb_value = self
+            .engine
+            .get_bytes(request.into_inner().key)
+            .map_err(kverror_to_status)?;
+        Ok(Response::new(GetBytesReply {
+            value: mb_value.map(|value| BytesValue { value }),
+        }))
+    }
+
+    async fn set_bytes(
+        &self,
+        request: Request<SetBytesRequest>,
+    ) -> Result<Response<SetReply>, Status> {
+        let req = request.into_inner();
+        self.engine
+            .set_bytes(req.key, req.value)
+            .map_err(kverror_to_status)?;
+        Ok(Response::new(SetReply {}))
+    }
+
+    async fn remove_bytes(
+        &self,
+        request: Request<RemoveBytesRequest>,
+    ) -> Result<Response<RemoveReply>, Status> {
+        match self.engine.remove_bytes(request.into_inner().key) {
+            Ok(()) => Ok(Response::new(RemoveReply { removed: true })),
+            Err(KvError::KeyNotFound) => Ok(Response::new(RemoveReply { removed: false })),
+            Err(other) => Err(kverror_to_status(other)),
+        }
+    }
+
+    async fn get_with_version(
+        &self,
+        request: Request<GetBytesRequest>,
+    ) -> Result<Response<GetWithVersionReply>, Status> {
+        let mb_value = self
+            .engine
+            .get_with_version(request.into_inner().key)
+            .map_err(kverror_to_status)?;
+        Ok(Response::new(GetWithVersionReply {
+            value: mb_value.map(|(value, version)| VersionedValue { value, version }),
+        }))
+    }
+
+    async fn compare_and_set(
+        &self,
+        request: Request<CompareAndSetRequest>,
+    ) -> Result<Response<CompareAndSetReply>, Status> {
+        let req = request.into_inner();
+        let expected = req.expected.map(|expected| expected.version);
+        let version = self
+            .engine
+            .compare_and_set(req.key, expected, req.value)
+            .map_err(kverror_to_status)?;
+        Ok(Response::new(CompareAndSetReply { version }))
+    }
+
+    async fn compare_and_remove(
+        &self,
+        request: Request<CompareAndRemoveRequest>,
+    ) -> Result<Response<CompareAndRemoveReply>, Status> {
+        let req = request.into_inner();
+        self.engine
+            .compare_and_remove(req.key, req.expected)
+            .map_err(kverror_to_status)?;
+        Ok(Response::new(CompareAndRemoveReply {}))
+    }
+
+    async fn batch(&self, request: Request<BatchRequest>) -> Result<Response<BatchReply>, Status> {
+        let mut batch = WriteBatch::new();
+        for op in request.into_inner().operations {
+            if op.remove {
+                batch.delete(op.key);
+            } else {
+                batch.put(op.key, op.value);
+            }
         }
+        self.engine.write_batch(batch).map_err(kverror_to_status)?;
+        Ok(Response::new(BatchReply {}))
+    }
+
+    type ScanStream = mpsc::Receiver<Result<KeyValue, Status>>;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let req = request.into_inner();
+        let limit = if req.limit == 0 {
+            None
+        } else {
+            Some(req.limit as usize)
+        };
+        let scan = if !req.prefix.is_empty() {
+            self.engine.scan_prefix(req.prefix, limit)
+        } else {
+            let end = if req.end.is_empty() {
+                Bound::Unbounded
+            } else {
+                Bound::Excluded(req.end)
+            };
+            self.engine.scan((Bound::Included(req.start), end), limit)
+        }
+        .map_err(kverror_to_status)?;
+
+        let (mut tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            for pair in scan {
+                let failed = pair.is_err();
+                let reply = pair
+                    .map(|(key, value)| KeyValue { key, value })
+                    .map_err(kverror_to_status);
+                // the client may have gone away already
+                if tx.send(reply).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(rx))
+    }
+
+    async fn ttl(&self, request: Request<TtlRequest>) -> Result<Response<TtlReply>, Status> {
+        let key = request.into_inner().key.into_bytes();
+        let reply = match self.engine.ttl(key) {
+            Ok(ttl) => TtlReply {
+                found: true,
+                expires: ttl.is_some(),
+                ttl_millis: ttl.map(|ttl| ttl.as_millis() as u64).unwrap_or(0),
+            },
+            Err(KvError::KeyNotFound) => TtlReply::default(),
+            Err(other) => return Err(kverror_to_status(other)),
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn persist(
+        &self,
+        request: Request<PersistRequest>,
+    ) -> Result<Response<PersistReply>, Status> {
+        match self.engine.persist(request.into_inner().key.into_bytes()) {
+            Ok(()) => Ok(Response::new(PersistReply { found: true })),
+            Err(KvError::KeyNotFound) => Ok(Response::new(PersistReply { found: false })),
+            Err(other) => Err(kverror_to_status(other)),
+        }
+    }
+
+    async fn backup(
+        &self,
+        request: Request<BackupRequest>,
+    ) -> Result<Response<BackupReply>, Status> {
+        let backup_dir = self.backup_dir.as_ref().ok_or_else(|| {
+            Status::new(
+                Code::FailedPrecondition,
+                "Backups are disabled, the server was started without --backup-dir",
+            )
+        })?;
+        let path = PathBuf::from(request.into_inner().path);
+        if path.as_os_str().is_empty() {
+            return Err(Status::new(Code::InvalidArgument, "No path given"));
+        }
+        // clients must not get out of the backup directory
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "The path must be relative to the backup directory and must not contain '..'",
+            ));
+        }
+        self.engine
+            .checkpoint(&backup_dir.join(path))
+            .map_err(kverror_to_status)?;
+        Ok(Response::new(BackupReply {}))
     }
 }
 
@@ -116,9 +353,86 @@ struct Opt {
     // The storage engine to use. Can be either 'kvs' or 'sled'
     #[structopt(long)]
     engine: Option<Engine>,
+
+    // Rotate the log file after this many commands
+    #[structopt(long = "segment-entries")]
+    segment_entries: Option<usize>,
+
+    // Rotate the log file once it's this many bytes long
+    #[structopt(long = "segment-bytes")]
+    segment_bytes: Option<u64>,
+
+    // Compact after this many log files were rotated
+    #[structopt(long = "compaction-trigger")]
+    compaction_trigger: Option<usize>,
+
+    // Only compact log files in which at least this fraction
+    // of the bytes is no longer needed
+    #[structopt(long = "compaction-stale-ratio")]
+    compaction_stale_ratio: Option<f64>,
+
+    // When to flush writes to disk. Can be 'always', 'never' or
+    // 'every:<milliseconds>'
+    #[structopt(long)]
+    durability: Option<Durability>,
+
+    // How new values are compressed. Can be 'none' or 'lz4'
+    #[structopt(long)]
+    compression: Option<Compression>,
+
+    // Keep this many bytes of hot keys and values in memory
+    #[structopt(long = "cache-bytes")]
+    cache_bytes: Option<usize>,
+
+    // Encrypt the data with the key in this file
+    #[structopt(long = "key-file", parse(from_os_str))]
+    key_file: Option<PathBuf>,
+
+    // A key the data was encrypted with before. Records encrypted
+    // with it are rewritten with the current one. Can be repeated
+    #[structopt(long = "old-key-file", parse(from_os_str))]
+    old_key_files: Vec<PathBuf>,
+
+    // Write backups into directories below this one. Without
+    // it, backups are refused
+    #[structopt(long = "backup-dir", parse(from_os_str))]
+    backup_dir: Option<PathBuf>,
+}
+
+impl Opt {
+    fn store_options(&self, logger: slog::Logger) -> Result<KvStoreOptions, KvError> {
+        let mut options = KvStoreOptions::default()
+            .durability(self.durability.unwrap_or(Durability::Never))
+            .compression(self.compression.unwrap_or(Compression::None))
+            .logger(logger);
+        if let Some(entries) = self.segment_entries {
+            options = options.segment_max_entries(entries);
+        }
+        if let Some(bytes) = self.segment_bytes {
+            options = options.segment_max_bytes(bytes);
+        }
+        if let Some(rotations) = self.compaction_trigger {
+            options = options.compaction_trigger(rotations);
+        }
+        if let Some(ratio) = self.compaction_stale_ratio {
+            options = options.compaction_stale_ratio(ratio);
+        }
+        if let Some(bytes) = self.cache_bytes {
+            options = options.cache_capacity(bytes);
+        }
+        if let Some(path) = &self.key_file {
+            options = options.encryption_key(EncryptionKey::from_file(path)?);
+        }
+        let old_keys = self
+            .old_key_files
+            .iter()
+            .map(|path| EncryptionKey::from_file(path))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(options.old_encryption_keys(old_keys))
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum Engine {
     Kvs,
     Sled,
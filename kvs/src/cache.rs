@@ -0,0 +1,184 @@
+// Value cache
+//
+// Values that are read often are kept in memory, so they don't
+// have to be read and decoded again. Entries are keyed by key
+// and version and there is at most one version per key. Writes
+// remove the cached value of their key once the index points to
+// the new one, and a value that was read is only cached if the
+// index still points to it. So whatever is in the cache is what
+// a reader would have found in the file.
+//
+// Eviction uses CLOCK: entries sit in a ring and are marked
+// whenever they are read. A hand sweeps over the ring, unmarks
+// the marked entries and evicts the first one that isn't. That's
+// close to LRU, but a read only needs to set a flag.
+//
+// The cache is split into shards by the hash of the key, so
+// readers of different keys rarely wait for each other.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::stats::CacheStats;
+
+const SHARDS: usize = 16;
+
+pub struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Shard {
+    // in bytes of keys and values
+    capacity: usize,
+    size: usize,
+    // the position of each key in the ring
+    slots: HashMap<Vec<u8>, usize>,
+    ring: Vec<Option<Slot>>,
+    // positions in the ring that are free
+    free: Vec<usize>,
+    hand: usize,
+}
+
+struct Slot {
+    key: Vec<u8>,
+    version: u64,
+    value: Vec<u8>,
+    referenced: bool,
+}
+
+impl Slot {
+    fn size(&self) -> usize {
+        self.key.len() + self.value.len()
+    }
+}
+
+impl ValueCache {
+    // a capacity of zero disables the cache
+    pub fn new(capacity: usize) -> ValueCache {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    capacity: capacity / SHARDS,
+                    size: 0,
+                    slots: HashMap::new(),
+                    ring: Vec::new(),
+                    free: Vec::new(),
+                    hand: 0,
+                })
+            })
+            .collect();
+        ValueCache {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        &self.shards[crc32fast::hash(key) as usize % SHARDS]
+    }
+
+    pub fn get(&self, key: &[u8], version: u64) -> Option<Vec<u8>> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let found = match shard.slots.get(key) {
+            Some(&pos) => match &mut shard.ring[pos] {
+                Some(slot) if slot.version == version => {
+                    slot.referenced = true;
+                    Some(slot.value.clone())
+                }
+                _ => None,
+            },
+            None => None,
+        };
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    // caches the value unless is_current says that the index
+    // doesn't point to it anymore. it's called with the shard
+    // locked, so a write can't remove the value in between
+    pub fn insert<F>(&self, key: &[u8], version: u64, value: &[u8], is_current: F)
+    where
+        F: FnOnce() -> bool,
+    {
+        let mut shard = self.shard(key).lock().unwrap();
+        if key.len() + value.len() > shard.capacity || !is_current() {
+            return;
+        }
+        shard.remove(key);
+        let slot = Slot {
+            key: key.to_vec(),
+            version,
+            value: value.to_vec(),
+            referenced: false,
+        };
+        shard.evict_until_fits(slot.size());
+        shard.size += slot.size();
+        let pos = match shard.free.pop() {
+            Some(pos) => pos,
+            None => {
+                shard.ring.push(None);
+                shard.ring.len() - 1
+            }
+        };
+        shard.slots.insert(slot.key.clone(), pos);
+        shard.ring[pos] = Some(slot);
+    }
+
+    // must be called after the index was changed
+    pub fn invalidate(&self, key: &[u8]) {
+        self.shard(key).lock().unwrap().remove(key);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (mut entries, mut bytes) = (0, 0);
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            entries += shard.slots.len();
+            bytes += shard.size;
+        }
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries,
+            bytes,
+        }
+    }
+}
+
+impl Shard {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(pos) = self.slots.remove(key) {
+            if let Some(slot) = self.ring[pos].take() {
+                self.size -= slot.size();
+            }
+            self.free.push(pos);
+        }
+    }
+
+    // every round over the ring unmarks all entries, so
+    // this is done after two rounds at the latest
+    fn evict_until_fits(&mut self, needed: usize) {
+        while self.size + needed > self.capacity {
+            self.hand = (self.hand + 1) % self.ring.len();
+            let evict = match &mut self.ring[self.hand] {
+                Some(slot) if slot.referenced => {
+                    slot.referenced = false;
+                    None
+                }
+                Some(slot) => Some(slot.key.clone()),
+                None => None,
+            };
+            if let Some(key) = evict {
+                self.remove(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// The thread that runs the compaction in the background
+//
+// The store triggers a compaction whenever enough files
+// were rotated. The thread then runs the compaction while
+// the store keeps serving requests. Triggers that arrive
+// while a compaction is running are merged into one more
+// run afterwards.
+use slog::Logger;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+#[derive(Default)]
+struct State {
+    // somebody asked for a compaction that hasn't started yet
+    requested: bool,
+    // a compaction is in progress right now
+    running: bool,
+    paused: bool,
+    shutdown: bool,
+}
+
+// the part that is shared between the thread and everybody
+// who can trigger a compaction
+#[derive(Clone, Default)]
+pub struct CompactionTrigger {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl CompactionTrigger {
+    pub fn trigger(&self) {
+        let (lock, cvar) = &*self.state;
+        lock.lock().unwrap().requested = true;
+        cvar.notify_all();
+    }
+
+    fn update<F: FnOnce(&mut State)>(&self, f: F) {
+        let (lock, cvar) = &*self.state;
+        f(&mut lock.lock().unwrap());
+        cvar.notify_all();
+    }
+}
+
+// owns the compaction thread. the thread is stopped
+// when this is dropped
+pub struct Compactor {
+    trigger: CompactionTrigger,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    pub fn spawn<F>(trigger: CompactionTrigger, logger: Logger, mut compact: F) -> Compactor
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let state = trigger.state.clone();
+        let handle = thread::Builder::new()
+            .name("kvs-compactor".to_owned())
+            .spawn(move || {
+                let (lock, cvar) = &*state;
+                loop {
+                    {
+                        let mut state = lock.lock().unwrap();
+                        while !state.shutdown && (!state.requested || state.paused) {
+                            state = cvar.wait(state).unwrap();
+                        }
+                        if state.shutdown {
+                            break;
+                        }
+                        state.requested = false;
+                        state.running = true;
+                    }
+                    compact();
+                    lock.lock().unwrap().running = false;
+                    cvar.notify_all();
+                }
+                debug!(logger, "Compactor stopped");
+            })
+            .expect("failed to spawn compaction thread");
+        Compactor {
+            trigger,
+            handle: Some(handle),
+        }
+    }
+
+    // a running compaction is finished, but no new one is started
+    pub fn pause(&self) {
+        self.trigger.update(|state| state.paused = true);
+    }
+
+    pub fn resume(&self) {
+        self.trigger.update(|state| state.paused = false);
+    }
+
+    // blocks until there is nothing left to do. if paused,
+    // this only waits for the running compaction
+    pub fn wait(&self) {
+        let (lock, cvar) = &*self.trigger.state;
+        let mut state = lock.lock().unwrap();
+        while state.running || (state.requested && !state.paused) {
+            state = cvar.wait(state).unwrap();
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.trigger.update(|state| state.shutdown = true);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Compression of the values in the log files
+use std::fmt;
+use std::str::FromStr;
+
+/// How values are compressed before they are written
+///
+/// Every record says how its value is compressed, so a file may
+/// contain values with different compressions and the setting
+/// can be changed at any time. Compaction rewrites the values
+/// it moves with the current setting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    /// Values are written as they are
+    None,
+    /// Values are compressed with LZ4, which is fast,
+    /// but doesn't save as much as others
+    Lz4,
+}
+
+impl Compression {
+    // the id in the record header. it must fit into three bits
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    // returns the compression that was actually used, because
+    // values that don't get any smaller are kept as they are
+    pub(crate) fn compress(self, value: Vec<u8>) -> (Compression, Vec<u8>) {
+        match self {
+            Compression::None => (Compression::None, value),
+            Compression::Lz4 => {
+                let compressed = lz4_flex::compress_prepend_size(&value);
+                if compressed.len() < value.len() {
+                    (Compression::Lz4, compressed)
+                } else {
+                    (Compression::None, value)
+                }
+            }
+        }
+    }
+
+    pub(crate) fn decompress(self, stored: Vec<u8>) -> Result<Vec<u8>, String> {
+        match self {
+            Compression::None => Ok(stored),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&stored)
+                .map_err(|err| format!("cannot decompress value: {}", err)),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            other => Err(format!("Compression '{}' does not exist", other)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(fmt, "none"),
+            Compression::Lz4 => write!(fmt, "lz4"),
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! Encryption of the log files
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::engine::{KvError, Result};
+use crate::record::RecordError;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+// what is encrypted to tell whether a key is the right one
+const CHECK_PLAINTEXT: &[u8] = b"kvs encryption key check";
+
+// the number of bytes an encrypted payload is longer than
+// the plain one: key id, nonce and authentication tag
+pub(crate) const OVERHEAD: usize = 4 + NONCE_SIZE + TAG_SIZE;
+
+/// A key to encrypt the log files with
+///
+/// Keys are stored in files as 64 hexadecimal characters.
+///
+/// # Examples
+///
+/// ```
+///  # use kvs::EncryptionKey;
+///  let key = EncryptionKey::generate();
+///  let same = key.to_hex().parse::<EncryptionKey>().unwrap();
+///  assert_eq!(key.to_hex(), same.to_hex());
+/// ```
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_SIZE]);
+
+impl EncryptionKey {
+    /// Creates a new random key
+    pub fn generate() -> EncryptionKey {
+        let mut key = [0; KEY_SIZE];
+        key.copy_from_slice(&XChaCha20Poly1305::generate_key(&mut OsRng));
+        EncryptionKey(key)
+    }
+
+    /// Reads a key from a file that contains it in hexadecimal
+    pub fn from_file(path: &Path) -> Result<EncryptionKey> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|msg| KvError::WrongKey(format!("{}: {}", path.to_string_lossy(), msg)))
+    }
+
+    /// The key in hexadecimal, as it's stored in files
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.0)
+    }
+}
+
+impl std::str::FromStr for EncryptionKey {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<EncryptionKey, String> {
+        let s = s.trim();
+        if s.len() != KEY_SIZE * 2 || !s.is_ascii() {
+            return Err(format!("expected {} hexadecimal characters", KEY_SIZE * 2));
+        }
+        let mut key = [0; KEY_SIZE];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .map_err(|_| format!("invalid hexadecimal '{}'", &s[i * 2..i * 2 + 2]))?;
+        }
+        Ok(EncryptionKey(key))
+    }
+}
+
+// keys don't end up in logs by accident
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "EncryptionKey(..)")
+    }
+}
+
+struct KeyEntry {
+    id: u32,
+    check: Vec<u8>,
+    cipher: XChaCha20Poly1305,
+}
+
+impl KeyEntry {
+    fn new(key: &EncryptionKey) -> KeyEntry {
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&key.0));
+        // the nonce is fixed, so the check is the same every time.
+        // that's fine, because it's only ever used for this text
+        let check = cipher
+            .encrypt(&XNonce::default(), CHECK_PLAINTEXT)
+            .expect("encryption into a vec never fails");
+        // derived from the check rather than the key, so the
+        // id says nothing about the key that the check doesn't
+        let id = crc32fast::hash(&check);
+        KeyEntry { id, check, cipher }
+    }
+}
+
+// the key new records are encrypted with, if any, along
+// with the older ones that may still be needed to read
+#[derive(Default)]
+pub(crate) struct Keyring {
+    current: Option<KeyEntry>,
+    old: Vec<KeyEntry>,
+}
+
+impl Keyring {
+    pub(crate) fn new(current: Option<&EncryptionKey>, old: &[EncryptionKey]) -> Keyring {
+        Keyring {
+            current: current.map(KeyEntry::new),
+            old: old.iter().map(KeyEntry::new).collect(),
+        }
+    }
+
+    pub(crate) fn is_encrypting(&self) -> bool {
+        self.current.is_some()
+    }
+
+    // goes into the metadata, so a wrong key is detected
+    // before anything is read
+    pub(crate) fn current_check(&self) -> Option<String> {
+        self.current.as_ref().map(|key| to_hex(&key.check))
+    }
+
+    // whether the check belongs to the current key or an old one
+    pub(crate) fn knows_check(&self, check: &str, include_old: bool) -> bool {
+        let old: &[KeyEntry] = if include_old { &self.old } else { &[] };
+        self.current
+            .iter()
+            .chain(old)
+            .any(|key| to_hex(&key.check) == check)
+    }
+
+    // returns key id, nonce and ciphertext or None if
+    // there is no key to encrypt with
+    pub(crate) fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Option<Vec<u8>> {
+        let key = self.current.as_ref()?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = key
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("encryption into a vec never fails");
+        let mut sealed = Vec::with_capacity(OVERHEAD + plaintext.len());
+        sealed.extend_from_slice(&key.id.to_le_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Some(sealed)
+    }
+
+    // the reverse of seal
+    pub(crate) fn open(
+        &self,
+        aad: &[u8],
+        sealed: &[u8],
+    ) -> std::result::Result<Vec<u8>, RecordError> {
+        if sealed.len() < OVERHEAD {
+            return Err(RecordError::Invalid(
+                "encrypted payload too short".to_owned(),
+            ));
+        }
+        let mut id = [0; 4];
+        id.copy_from_slice(&sealed[..4]);
+        let id = u32::from_le_bytes(id);
+        let key = self
+            .current
+            .iter()
+            .chain(&self.old)
+            .find(|key| key.id == id)
+            .ok_or(RecordError::UnknownKey(id))?;
+        let nonce = XNonce::from_slice(&sealed[4..4 + NONCE_SIZE]);
+        key.cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &sealed[4 + NONCE_SIZE..],
+                    aad,
+                },
+            )
+            .map_err(|_| {
+                RecordError::Invalid("cannot decrypt, the record was tampered with".to_owned())
+            })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -1,13 +1,16 @@
 #![deny(missing_docs)]
+// derive(Fail) generates its impls inside a const block
+#![allow(non_local_definitions)]
 
 //! A key value store implementation for the course Practical Networked Applications from PingCAP
 //!
 //! # Examples
 //!
 //! ```
-//!  # use kvs::KvStore;
-//!  # use std::path::Path;
-//!  let mut kv = KvStore::open(Path::new("/tmp")).unwrap();
+//!  # use kvs::{KvStore, KvsEngine};
+//!  # use tempfile::TempDir;
+//!  # let dir = TempDir::new().unwrap();
+//!  let kv = KvStore::open(dir.path()).unwrap();
 //!  kv.set(String::from("foo"), String::from("bar"));
 //!  assert_eq!(Some(String::from("bar")), kv.get(String::from("foo")).unwrap());
 //!  kv.remove(String::from("foo"));
@@ -15,9 +18,13 @@
 //! ```
 use failure::Fail;
 use serde_json;
-use std::error::Error;
 use std::fmt;
 use std::io;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::batch::WriteBatch;
 
 /// Errors reported by this library
 #[derive(Debug, Fail)]
@@ -32,26 +39,95 @@ pub enum KvError {
         /// Underlying serde error
         cause: serde_json::error::Error,
     },
+    /// Some problem in the sled engine
+    SledError {
+        /// Underlying sled error
+        cause: sled::Error,
+    },
 
     /// Key was not found
     KeyNotFound,
 
     /// Something with the database seems inconsitent. Could be corrupted file or bug
     Consistency(String),
+
+    /// The directory is already used by another store, possibly in another process
+    Locked(PathBuf),
+
+    /// The store was opened read-only and cannot be modified
+    ReadOnly,
+
+    /// The value is not valid UTF-8 and can only be read as bytes
+    NotUtf8,
+
+    /// A conditional write failed, because the key was modified
+    VersionMismatch {
+        /// The version of the key or None if it doesn't exist
+        current: Option<u64>,
+    },
+
+    /// The directory contains the data of a different engine
+    WrongEngine {
+        /// The engine that tried to open the directory
+        expected: String,
+        /// The engine that wrote the directory
+        found: String,
+    },
+
+    /// The directory contains data in a format this version cannot read
+    WrongFormat {
+        /// The newest format version this engine can read
+        expected: u32,
+        /// The format version of the directory
+        found: u32,
+    },
+
+    /// The engine does not support this operation
+    Unsupported(String),
+
+    /// The encryption key is missing, invalid or not the
+    /// one the directory was encrypted with
+    WrongKey(String),
+
+    /// An option the store was opened with is not valid
+    InvalidOption(String),
 }
 
 impl fmt::Display for KvError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         use KvError::*;
         match self {
-            IOError { cause } => write!(fmt, "IOError: {}", cause.description().to_owned()),
-            SerializationError { cause } => write!(
-                fmt,
-                "SerializationError: {}",
-                cause.description().to_owned()
-            ),
+            IOError { cause } => write!(fmt, "IOError: {}", cause),
+            SerializationError { cause } => write!(fmt, "SerializationError: {}", cause),
+            SledError { cause } => write!(fmt, "SledError: {}", cause),
             KeyNotFound => write!(fmt, "Key not found"),
             Consistency(msg) => write!(fmt, "ConsistencyError: {}", msg),
+            Locked(dir) => write!(
+                fmt,
+                "Directory {} is already in use by another store",
+                dir.to_string_lossy()
+            ),
+            ReadOnly => write!(fmt, "Store is read-only"),
+            NotUtf8 => write!(fmt, "Value is not valid UTF-8"),
+            VersionMismatch {
+                current: Some(version),
+            } => {
+                write!(fmt, "Key was modified, it's at version {} now", version)
+            }
+            VersionMismatch { current: None } => write!(fmt, "Key does not exist"),
+            WrongEngine { expected, found } => write!(
+                fmt,
+                "Directory was written by engine '{}' and cannot be opened with '{}'",
+                found, expected
+            ),
+            WrongFormat { expected, found } => write!(
+                fmt,
+                "Directory has format version {}, but only versions up to {} are supported",
+                found, expected
+            ),
+            Unsupported(op) => write!(fmt, "Operation '{}' is not supported by this engine", op),
+            WrongKey(msg) => write!(fmt, "Wrong encryption key: {}", msg),
+            InvalidOption(msg) => write!(fmt, "Invalid option: {}", msg),
         }
     }
 }
@@ -60,11 +136,124 @@ impl fmt::Display for KvError {
 pub type Result<T> = std::result::Result<T, KvError>;
 
 /// a pluggable storage engine for this kv store
-pub trait KvsEngine {
-    ///
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    ///
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    ///
-    fn remove(&mut self, key: String) -> Result<()>;
+///
+/// Engines are handles to shared state: cloning one is cheap
+/// and gives another handle to the same data, which may be
+/// used from a different thread.
+///
+/// Keys and values are arbitrary bytes. The methods taking
+/// strings are a convenience for the common case of text.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of a key, overwriting any previous value
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    /// Returns the value of a key or None if it does not exist
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    /// Removes a key. Fails with `KvError::KeyNotFound` if it does not exist
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+    /// Makes sure that all writes so far are on disk
+    fn flush(&self) -> Result<()>;
+
+    /// Applies all changes of the batch or none of them
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Returns the value of a key along with its version or None
+    /// if it does not exist. The version of a key grows with every
+    /// write to it, even if it was removed in between.
+    fn get_with_version(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>>;
+
+    /// Sets the value of a key if it's still at the expected version.
+    /// `None` means that the key must not exist. Fails with
+    /// `KvError::VersionMismatch` otherwise. Returns the new version.
+    fn compare_and_set(&self, key: Vec<u8>, expected: Option<u64>, value: Vec<u8>) -> Result<u64>;
+
+    /// Removes a key if it's still at the expected version. Fails
+    /// with `KvError::VersionMismatch` otherwise.
+    fn compare_and_remove(&self, key: Vec<u8>, expected: u64) -> Result<()>;
+
+    /// Sets the value of a key that is removed automatically once the
+    /// ttl has passed. Any later write to the key replaces the ttl.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Returns how long the key has left or None if it doesn't expire.
+    /// Fails with `KvError::KeyNotFound` if it does not exist
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// Removes the ttl of a key, so that it doesn't expire anymore.
+    /// Fails with `KvError::KeyNotFound` if it does not exist
+    fn persist(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Writes a consistent copy of the data into the target
+    /// directory, which can then be opened like any other
+    fn checkpoint(&self, target: &Path) -> Result<()>;
+
+    /// Sets a value while restoring a backup. Engines that can keep
+    /// the version do so and only give out higher ones afterwards,
+    /// all others give the value a new one. This is meant for
+    /// loading into an empty store.
+    fn restore(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        _version: Option<u64>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<()> {
+        match expires_at {
+            None => self.set_bytes(key, value),
+            Some(at) => {
+                // if it has expired already, it's gone right away
+                let ttl = at.duration_since(SystemTime::now()).unwrap_or_default();
+                self.set_with_ttl(key, value, ttl)
+            }
+        }
+    }
+
+    /// Iterator over key value pairs in key order
+    type Scan: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send + 'static;
+
+    /// Returns the keys in the range along with their values in key
+    /// order, but no more than `limit` if there is a limit. Writes
+    /// that happen while iterating may or may not be seen.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<Self::Scan>;
+
+    /// Like `scan`, but for all keys that start with the prefix
+    fn scan_prefix(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<Self::Scan> {
+        match prefix_end(&prefix) {
+            Some(end) => self.scan(prefix..end, limit),
+            None => self.scan(prefix.., limit),
+        }
+    }
+
+    /// Like `set_bytes`, but for text
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Like `get_bytes`, but for text. Fails with `KvError::NotUtf8`
+    /// if the value is not valid UTF-8
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            None => Ok(None),
+            Some(value) => String::from_utf8(value)
+                .map(Some)
+                .map_err(|_| KvError::NotUtf8),
+        }
+    }
+
+    /// Like `remove_bytes`, but for text
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+}
+
+// the smallest key that is bigger than all keys starting with the
+// prefix. there is none if the prefix consists of 0xff only
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
 }
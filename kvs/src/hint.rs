@@ -0,0 +1,222 @@
+// Hint files
+//
+// For every immutable file N.immutable, we write a hint
+// file N.hint that contains the final state of every key
+// in that file, but not the values. When opening the
+// store, reading the hints is enough to build the index,
+// so we don't have to go through all values of all files.
+//
+//  +-------+----------------+-------------+---------+-----+-------+
+//  | magic | segment length | entry count | entries | ... | crc32 |
+//  |   4   |       8        |      8      |         |     |   4   |
+//  +-------+----------------+-------------+---------+-----+-------+
+//
+// with every entry being:
+//
+//  +------+---------+--------+------+---------+-----+
+//  | type | version | offset | size | key len | key |
+//  |  1   |    8    |   8    |  4   |    4    |     |
+//  +------+---------+--------+------+---------+-----+
+//
+// entries of values that expire are followed by the time
+// they expire at. The size of a 'Remove' is the size of
+// the command, or 0 in hints of older versions. Its offset
+// is not used.
+//
+// The segment length is the length of the immutable file
+// at the time the hint was written. If that does not match
+// anymore, or the checksum is wrong, the hint is stale and
+// must not be used.
+//
+// The hints of an encrypted store contain the keys, so they
+// are encrypted as a whole and prefixed with another magic.
+// Such a store ignores hints that are not encrypted, since
+// anyone could have written those.
+use crc32fast::Hasher;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::encryption::Keyring;
+
+const MAGIC: &[u8; 4] = b"KVSH";
+const MAGIC_ENCRYPTED: &[u8; 4] = b"KVSE";
+
+const TYPE_SET: u8 = 1;
+const TYPE_REMOVE: u8 = 2;
+const TYPE_SET_EXPIRING: u8 = 3;
+
+// the final state of a key in a log file
+#[derive(Debug)]
+pub enum Hint {
+    // the latest command was a 'Set' at this offset
+    Set {
+        offset: u64,
+        version: u64,
+        size: u32,
+        expires_at: Option<u64>,
+    },
+    // the latest command was a 'Remove' of this size
+    Remove {
+        version: u64,
+        size: u32,
+    },
+}
+
+pub fn hint_path(immutable: &Path) -> PathBuf {
+    immutable.with_extension("hint")
+}
+
+// writes the hints of an immutable file. the hints are
+// first written to a temporary file, so a crash never
+// leaves a half written hint file behind
+pub fn write_hints(
+    immutable: &Path,
+    hints: &HashMap<Vec<u8>, Hint>,
+    keys: &Keyring,
+) -> io::Result<()> {
+    let segment_length = fs::metadata(immutable)?.len();
+
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&segment_length.to_le_bytes());
+    buf.extend_from_slice(&(hints.len() as u64).to_le_bytes());
+    for (key, hint) in hints {
+        let (hint_type, version, offset, size, expires_at) = match hint {
+            Hint::Set {
+                offset,
+                version,
+                size,
+                expires_at: None,
+            } => (TYPE_SET, *version, *offset, *size, None),
+            Hint::Set {
+                offset,
+                version,
+                size,
+                expires_at,
+            } => (TYPE_SET_EXPIRING, *version, *offset, *size, *expires_at),
+            Hint::Remove { version, size } => (TYPE_REMOVE, *version, 0, *size, None),
+        };
+        buf.push(hint_type);
+        buf.extend_from_slice(&version.to_le_bytes());
+        buf.extend_from_slice(&offset.to_le_bytes());
+        buf.extend_from_slice(&size.to_le_bytes());
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        if let Some(expires_at) = expires_at {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+    }
+    let mut hasher = Hasher::new();
+    hasher.update(&buf);
+    buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+    let buf = match keys.seal(MAGIC_ENCRYPTED, &buf) {
+        Some(sealed) => [&MAGIC_ENCRYPTED[..], &sealed].concat(),
+        None => buf,
+    };
+
+    let path = hint_path(immutable);
+    let tmp_path = path.with_extension("hint.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+// reads the hints of an immutable file. returns None if
+// there is no hint file or if it cannot be trusted
+pub fn read_hints(immutable: &Path, keys: &Keyring) -> io::Result<Option<HashMap<Vec<u8>, Hint>>> {
+    let mut buf = Vec::new();
+    match File::open(hint_path(immutable)) {
+        Ok(mut file) => file.read_to_end(&mut buf)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let buf = match buf.get(..4) {
+        Some(magic) if magic == MAGIC_ENCRYPTED => {
+            match keys.open(MAGIC_ENCRYPTED, &buf[4..]) {
+                Ok(plain) => plain,
+                // the log tells what's wrong with the key
+                Err(_) => return Ok(None),
+            }
+        }
+        _ if keys.is_encrypting() => return Ok(None),
+        _ => buf,
+    };
+    let segment_length = fs::metadata(immutable)?.len();
+    Ok(parse_hints(&buf, segment_length))
+}
+
+fn parse_hints(buf: &[u8], segment_length: u64) -> Option<HashMap<Vec<u8>, Hint>> {
+    if buf.len() < 24 || &buf[..4] != MAGIC {
+        return None;
+    }
+    let (content, crc) = buf.split_at(buf.len() - 4);
+    let mut hasher = Hasher::new();
+    hasher.update(content);
+    if hasher.finalize().to_le_bytes() != crc {
+        return None;
+    }
+
+    let mut cursor = Cursor {
+        buf: content,
+        at: 4,
+    };
+    if cursor.u64()? != segment_length {
+        return None;
+    }
+    let count = cursor.u64()?;
+    let mut hints = HashMap::new();
+    for _ in 0..count {
+        let hint_type = cursor.bytes(1)?[0];
+        let version = cursor.u64()?;
+        let offset = cursor.u64()?;
+        let size = cursor.u32()?;
+        let key_len = cursor.u32()? as usize;
+        let key = cursor.bytes(key_len)?.to_vec();
+        let hint = match hint_type {
+            TYPE_SET => Hint::Set {
+                offset,
+                version,
+                size,
+                expires_at: None,
+            },
+            TYPE_SET_EXPIRING => Hint::Set {
+                offset,
+                version,
+                size,
+                expires_at: Some(cursor.u64()?),
+            },
+            TYPE_REMOVE => Hint::Remove { version, size },
+            _ => return None,
+        };
+        hints.insert(key, hint);
+    }
+    Some(hints)
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.at..self.at + n)?;
+        self.at += n;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Some(u64::from_le_bytes(bytes))
+    }
+}
@@ -3,8 +3,27 @@ extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
 
+mod arena;
+pub mod batch;
+mod cache;
+mod compactor;
+pub mod compression;
+pub mod encryption;
 pub mod engine;
+mod hint;
+mod meta;
+pub mod options;
+mod record;
+pub mod sled_engine;
+pub mod stats;
 pub mod store;
+mod syncer;
 
+pub use batch::WriteBatch;
+pub use compression::Compression;
+pub use encryption::EncryptionKey;
 pub use engine::{KvsEngine, Result};
+pub use options::{Durability, KvStoreOptions};
+pub use sled_engine::SledKvsEngine;
+pub use stats::KvStoreStats;
 pub use store::KvStore;
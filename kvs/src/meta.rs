@@ -0,0 +1,205 @@
+// The metadata of a data directory
+//
+// The first engine that opens a directory writes a small
+// file that says which engine it is and which version of
+// the on-disk format it uses. Every engine checks that file
+// when opening a directory and refuses to touch the data
+// of another engine or of a newer format. Older formats
+// are upgraded.
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::engine::{KvError, Result};
+use crate::record;
+
+const META_FILE_NAME: &str = "META";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Meta {
+    pub engine: String,
+    pub format_version: u32,
+    // no write ever got a version at or above this one. only
+    // the kvs engine keeps track of it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_version: Option<u64>,
+    // the data is encrypted with the key this check belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_check: Option<String>,
+    // some data may still be encrypted with the keys these
+    // checks belong to, until all files were rewritten
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub old_key_checks: Vec<String>,
+    // a compaction that is replacing files right now. only
+    // the kvs engine has those
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge: Option<PendingMerge>,
+}
+
+// the files a compaction replaces with the ones it merged
+// them into. it's written before the first file is touched,
+// so a crash in the middle can be finished on the next start
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingMerge {
+    // the counters of the files that are replaced
+    pub inputs: Vec<u64>,
+    // the counters of the merged files. each one takes
+    // the place of the newest input it contains
+    pub outputs: Vec<u64>,
+}
+
+// makes sure the directory belongs to this engine and format.
+// an empty directory is claimed, one with data but without
+// metadata is treated like one of the first format
+pub fn check_or_init(
+    dir: &Path,
+    engine: &str,
+    format_version: u32,
+    read_only: bool,
+) -> Result<Meta> {
+    let mut meta = match read_meta(dir)? {
+        Some(meta) => meta,
+        None => match detect_engine(dir)? {
+            None => {
+                let meta = Meta {
+                    engine: engine.to_owned(),
+                    format_version,
+                    next_version: None,
+                    key_check: None,
+                    old_key_checks: Vec::new(),
+                    merge: None,
+                };
+                if !read_only {
+                    write_meta(dir, &meta)?;
+                }
+                return Ok(meta);
+            }
+            // directories from before there was any metadata
+            Some(other) => Meta {
+                engine: other.to_owned(),
+                format_version: detect_format(dir, other)?,
+                next_version: None,
+                key_check: None,
+                old_key_checks: Vec::new(),
+                merge: None,
+            },
+        },
+    };
+
+    if meta.engine != engine {
+        return Err(KvError::WrongEngine {
+            expected: engine.to_owned(),
+            found: meta.engine,
+        });
+    }
+    // the first version wrote JSON, which can't be read anymore
+    if meta.format_version == 0 || meta.format_version > format_version {
+        return Err(KvError::WrongFormat {
+            expected: format_version,
+            found: meta.format_version,
+        });
+    }
+    // older formats can still be read, but once we write, older
+    // versions of the engine must not open the directory anymore
+    if meta.format_version < format_version && !read_only {
+        meta.format_version = format_version;
+        write_meta(dir, &meta)?;
+    }
+    Ok(meta)
+}
+
+pub fn write_next_version(dir: &Path, next_version: u64) -> Result<()> {
+    let mut meta = expect_meta(dir)?;
+    meta.next_version = Some(next_version);
+    write_meta(dir, &meta)
+}
+
+pub fn write_key_checks(dir: &Path, key_check: Option<String>, old: Vec<String>) -> Result<()> {
+    let mut meta = expect_meta(dir)?;
+    meta.key_check = key_check;
+    meta.old_key_checks = old;
+    write_meta(dir, &meta)
+}
+
+// fails if there is one already, because that one
+// has to be finished first
+pub fn begin_merge(dir: &Path, merge: &PendingMerge) -> Result<()> {
+    let mut meta = expect_meta(dir)?;
+    if meta.merge.is_some() {
+        return Err(KvError::Consistency(
+            "An earlier compaction is unfinished".to_owned(),
+        ));
+    }
+    meta.merge = Some(merge.clone());
+    write_meta(dir, &meta)
+}
+
+pub fn end_merge(dir: &Path) -> Result<()> {
+    let mut meta = expect_meta(dir)?;
+    meta.merge = None;
+    write_meta(dir, &meta)
+}
+
+pub fn expect_meta(dir: &Path) -> Result<Meta> {
+    read_meta(dir)?
+        .ok_or_else(|| KvError::Consistency(format!("No metadata in {}", dir.to_string_lossy())))
+}
+
+fn read_meta(dir: &Path) -> Result<Option<Meta>> {
+    match File::open(dir.join(META_FILE_NAME)) {
+        Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn write_meta(dir: &Path, meta: &Meta) -> Result<()> {
+    let path = dir.join(META_FILE_NAME);
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec(meta)?)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+// the format of a directory from before there was any
+// metadata. that's either the first binary format or the
+// JSON of the version before it
+fn detect_format(dir: &Path, engine: &str) -> io::Result<u32> {
+    if engine != "kvs" {
+        return Ok(1);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_log = path.extension().and_then(|e| e.to_str()) == Some("immutable")
+            || path.file_name().and_then(|n| n.to_str()) == Some("db.active");
+        if is_log && record::is_json_log(File::open(&path)?) {
+            return Ok(0);
+        }
+    }
+    Ok(1)
+}
+
+// guesses the engine from the files in the directory
+fn detect_engine(dir: &Path) -> io::Result<Option<&'static str>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("immutable") | Some("hint") => return Ok(Some("kvs")),
+            _ if name == "db.active" => return Ok(Some("kvs")),
+            _ if name == "conf" || name == "db" || name == "blobs" || name.starts_with("snap.") => {
+                return Ok(Some("sled"))
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
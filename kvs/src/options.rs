@@ -0,0 +1,208 @@
+//! Tuning knobs for the `KvStore`
+use crate::compression::Compression;
+use crate::encryption::EncryptionKey;
+use crate::slog::Drain;
+use slog::Logger;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// When the log files are flushed to disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// After every write. A write that returned successfully
+    /// survives a crash of the machine. Concurrent writes share
+    /// the same flush.
+    Always,
+    /// Periodically in the background. Writes of the last interval
+    /// may be lost if the machine goes down.
+    Every(Duration),
+    /// Whenever the operating system decides to do so. Writes
+    /// that returned successfully survive a crash of the process,
+    /// but may be lost if the machine goes down.
+    Never,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Durability, String> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "never" => Ok(Durability::Never),
+            other if other.starts_with("every:") => match other["every:".len()..].parse::<u64>() {
+                Ok(0) => Err(format!("Interval in '{}' must not be zero", other)),
+                Ok(millis) => Ok(Durability::Every(Duration::from_millis(millis))),
+                Err(_) => Err(format!(
+                    "Invalid interval in '{}', expected milliseconds",
+                    other
+                )),
+            },
+            other => Err(format!("Durability '{}' does not exist", other)),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Durability::Always => write!(fmt, "always"),
+            Durability::Every(interval) => write!(fmt, "every:{}", interval.as_millis()),
+            Durability::Never => write!(fmt, "never"),
+        }
+    }
+}
+
+/// Options for opening a `KvStore`
+///
+/// # Examples
+///
+/// ```
+///  # use kvs::{Durability, KvStore, KvStoreOptions};
+///  # use tempfile::TempDir;
+///  # let dir = TempDir::new().unwrap();
+///  let options = KvStoreOptions::default()
+///      .segment_max_entries(10_000)
+///      .segment_max_bytes(64 * 1024 * 1024)
+///      .durability(Durability::Always);
+///  let kv = KvStore::open_with(dir.path(), options).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(crate) segment_max_entries: Option<usize>,
+    pub(crate) segment_max_bytes: Option<u64>,
+    pub(crate) compaction_trigger: usize,
+    pub(crate) compaction_stale_ratio: f64,
+    pub(crate) durability: Durability,
+    pub(crate) compression: Compression,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) old_encryption_keys: Vec<EncryptionKey>,
+    pub(crate) cache_capacity: usize,
+    pub(crate) read_only: bool,
+    pub(crate) logger: Option<Logger>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            segment_max_entries: Some(150),
+            segment_max_bytes: None,
+            compaction_trigger: 5,
+            compaction_stale_ratio: 0.5,
+            durability: Durability::Never,
+            compression: Compression::None,
+            encryption_key: None,
+            old_encryption_keys: Vec::new(),
+            cache_capacity: 16 * 1024 * 1024,
+            read_only: false,
+            logger: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// The active log file is rotated once it contains this many
+    /// commands. `None` means there is no limit on the number of
+    /// commands. Defaults to 150.
+    pub fn segment_max_entries<T: Into<Option<usize>>>(mut self, entries: T) -> KvStoreOptions {
+        self.segment_max_entries = entries.into();
+        self
+    }
+
+    /// The active log file is rotated once it is this many bytes
+    /// long. `None` means there is no limit on the size, which is
+    /// the default.
+    pub fn segment_max_bytes<T: Into<Option<u64>>>(mut self, bytes: T) -> KvStoreOptions {
+        self.segment_max_bytes = bytes.into();
+        self
+    }
+
+    /// A compaction is started after this many log files were
+    /// rotated since the last one. Defaults to 5.
+    pub fn compaction_trigger(mut self, rotations: usize) -> KvStoreOptions {
+        self.compaction_trigger = rotations;
+        self
+    }
+
+    /// A compaction only rewrites the log files in which at least
+    /// this fraction of the bytes is no longer needed. Zero means
+    /// any file with a single stale byte. Defaults to 0.5. Opening
+    /// the store fails with `KvError::InvalidOption` if the ratio is
+    /// not between zero and one.
+    pub fn compaction_stale_ratio(mut self, ratio: f64) -> KvStoreOptions {
+        self.compaction_stale_ratio = ratio;
+        self
+    }
+
+    /// When writes are flushed to disk. Defaults to `Durability::Never`.
+    /// Opening the store fails with `KvError::InvalidOption` if
+    /// the interval of `Durability::Every` is zero.
+    pub fn durability(mut self, durability: Durability) -> KvStoreOptions {
+        self.durability = durability;
+        self
+    }
+
+    /// How new values are compressed. Values that were written
+    /// with a different setting can still be read. Defaults to
+    /// `Compression::None`.
+    pub fn compression(mut self, compression: Compression) -> KvStoreOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypts new records with this key. Opening a directory
+    /// that was encrypted with another key fails with
+    /// `KvError::WrongKey`, unless that key is one of the old
+    /// ones. Unencrypted directories are encrypted from now on.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> KvStoreOptions {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Keys the directory was encrypted with before. Records
+    /// encrypted with them can still be read and compaction
+    /// rewrites them with the current key, which is done in the
+    /// background right after opening. Once it's finished, the
+    /// old keys are no longer needed.
+    pub fn old_encryption_keys(mut self, keys: Vec<EncryptionKey>) -> KvStoreOptions {
+        self.old_encryption_keys = keys;
+        self
+    }
+
+    /// How many bytes of keys and values are kept in memory
+    /// to answer reads of hot keys without going to the file.
+    /// Zero disables the cache. Defaults to 16 MiB.
+    pub fn cache_capacity(mut self, bytes: usize) -> KvStoreOptions {
+        self.cache_capacity = bytes;
+        self
+    }
+
+    /// Opens the store for reading only. Other read-only stores
+    /// may use the same directory at the same time, but no store
+    /// that writes. Writes fail with `KvError::ReadOnly` and the
+    /// files are never modified, not even to recover from a crash.
+    /// No files are created either, so checkpoints and directories
+    /// that can't be written to can be opened as well.
+    pub fn read_only(mut self, read_only: bool) -> KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// The logger of the store. If none is set, the store logs
+    /// to the terminal.
+    pub fn logger(mut self, logger: Logger) -> KvStoreOptions {
+        self.logger = Some(logger);
+        self
+    }
+
+    pub(crate) fn logger_or_default(&self) -> Logger {
+        match &self.logger {
+            Some(logger) => logger.clone(),
+            None => {
+                let decorator = slog_term::TermDecorator::new().build();
+                let drain = slog_term::FullFormat::new(decorator).build().fuse();
+                let drain = slog_async::Async::new(drain).build().fuse();
+                slog::Logger::root(drain, o!("component" => "engine"))
+            }
+        }
+    }
+}
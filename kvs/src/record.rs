@@ -0,0 +1,482 @@
+// Binary format of the records in the log files
+//
+// Every command is written as one record that consists
+// of a fixed size header followed by the key and the
+// value. All integers are little endian.
+//
+//  +-------+------+---------+---------+-----------+------------+-----+-------+
+//  | crc32 | type | version | key len | value len | header crc | key | value |
+//  |   4   |  1   |    8    |    4    |     4     |     4      |     |       |
+//  +-------+------+---------+---------+-----------+------------+-----+-------+
+//
+// The checksum covers everything after itself, so a
+// flipped bit in the header is detected as well as one
+// in the payload.
+//
+// The header checksum covers the type and the lengths. It
+// is checked before the lengths are trusted, so a record
+// that ends after the file is known to be torn and not just
+// a record with a broken length. Records written before it
+// existed don't have the header crc and the 0x08 bit of
+// their type is not set.
+//
+// A value that expires is written with a different type
+// and the time it expires at, in milliseconds since the
+// epoch, in the first 8 bytes of the value.
+//
+// The upper four bits of the type are flags. Three of them
+// say how the value is compressed. The time a value expires
+// at is never compressed, only what comes after it.
+//
+// The highest one says that the record is encrypted. Its key
+// length is zero then and the value contains the id of the
+// encryption key, a nonce and the encrypted key length, key
+// and value. The header is authenticated along with them.
+//
+//  +--------+-------+------------------------------------+
+//  | key id | nonce | encrypted(key len | key | value)   |
+//  |   4    |  24   |                                    |
+//  +--------+-------+------------------------------------+
+//
+// A batch is a record without key whose value consists
+// of the records of its commands. Since the batch has a
+// checksum of its own, it is either read completely or
+// not at all.
+use crc32fast::Hasher;
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::compression::Compression;
+use crate::encryption::{self, Keyring};
+
+pub const HEADER_SIZE: usize = 25;
+// the header without the header crc
+const UNCHECKED_HEADER_SIZE: usize = 21;
+
+const TYPE_SET: u8 = 1;
+const TYPE_REMOVE: u8 = 2;
+const TYPE_BATCH: u8 = 3;
+const TYPE_SET_EXPIRING: u8 = 4;
+const TYPE_MASK: u8 = 0x07;
+const HEADER_CHECKED: u8 = 0x08;
+const COMPRESSION_MASK: u8 = 0x07;
+const ENCRYPTED: u8 = 0x80;
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    Set {
+        key: Vec<u8>,
+        // as it is stored, so it must be
+        // decompressed before it's used
+        value: Vec<u8>,
+        version: u64,
+        // milliseconds since the epoch
+        expires_at: Option<u64>,
+        compression: Compression,
+    },
+
+    Remove {
+        key: Vec<u8>,
+        version: u64,
+    },
+
+    // never contains another batch
+    Batch {
+        commands: Vec<Command>,
+    },
+}
+
+// a command as it was read from a file
+pub struct Record {
+    pub cmd: Command,
+    pub length: u64,
+    // older records have a shorter header
+    header_len: u64,
+}
+
+// reasons why a record could not be read. the caller
+// knows the file and offset, so it is responsible for
+// turning this into a meaningful error
+#[derive(Debug)]
+pub enum RecordError {
+    // the file ended in the middle of the record. if the
+    // header was complete, its checksum was fine
+    Truncated,
+    // the checksum stored in the header does not match
+    Checksum { expected: u32, actual: u32 },
+    // the record is intact, but its contents make no sense
+    Invalid(String),
+    // the record is encrypted with a key we don't have
+    UnknownKey(u32),
+    Io(io::Error),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordError::Truncated => write!(fmt, "truncated record"),
+            RecordError::Checksum { expected, actual } => write!(
+                fmt,
+                "checksum mismatch (expected {:08x}, found {:08x})",
+                expected, actual
+            ),
+            RecordError::Invalid(msg) => write!(fmt, "invalid record: {}", msg),
+            RecordError::UnknownKey(id) => write!(fmt, "encrypted with unknown key {:08x}", id),
+            RecordError::Io(cause) => write!(fmt, "{}", cause),
+        }
+    }
+}
+
+impl Command {
+    // encrypts the record if there is a key to do so
+    pub fn encode(&self, keys: &Keyring) -> Vec<u8> {
+        let batch;
+        let expiring;
+        let (record_type, version, key, value) = match self {
+            Command::Set {
+                key,
+                value,
+                version,
+                expires_at: None,
+                compression,
+            } => (
+                TYPE_SET | compression.id() << 4,
+                *version,
+                &key[..],
+                &value[..],
+            ),
+            Command::Set {
+                key,
+                value,
+                version,
+                expires_at: Some(expires_at),
+                compression,
+            } => {
+                expiring = [&expires_at.to_le_bytes()[..], value].concat();
+                (
+                    TYPE_SET_EXPIRING | compression.id() << 4,
+                    *version,
+                    &key[..],
+                    &expiring[..],
+                )
+            }
+            Command::Remove { key, version } => (TYPE_REMOVE, *version, &key[..], &[][..]),
+            Command::Batch { commands } => {
+                batch = commands
+                    .iter()
+                    .flat_map(|cmd| cmd.encode(keys))
+                    .collect::<Vec<_>>();
+                (TYPE_BATCH, 0, &[][..], &batch[..])
+            }
+        };
+        // the commands of a batch are encrypted on their own
+        let mut buf = if keys.is_encrypting() && record_type != TYPE_BATCH {
+            let plaintext = [&(key.len() as u32).to_le_bytes()[..], key, value].concat();
+            let sealed_len = encryption::OVERHEAD + plaintext.len();
+            let mut buf = header(record_type | ENCRYPTED, version, 0, sealed_len);
+            let sealed = keys
+                .seal(&buf[4..UNCHECKED_HEADER_SIZE], &plaintext)
+                .expect("there is a key to encrypt with");
+            buf.extend_from_slice(&sealed);
+            buf
+        } else {
+            let mut buf = header(record_type, version, key.len(), value.len());
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(value);
+            buf
+        };
+        let crc = checksum(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    // reads the next record from the reader. returns None if the
+    // reader is at its end before the first byte of the record
+    pub fn decode<R: Read>(reader: &mut R, keys: &Keyring) -> Result<Option<Record>, RecordError> {
+        let mut header = [0; HEADER_SIZE];
+        match read_fully(reader, &mut header[..UNCHECKED_HEADER_SIZE])? {
+            0 => return Ok(None),
+            n if n < UNCHECKED_HEADER_SIZE => return Err(RecordError::Truncated),
+            _ => {}
+        }
+        let header = if header[4] & HEADER_CHECKED != 0 {
+            if read_fully(reader, &mut header[UNCHECKED_HEADER_SIZE..])? < 4 {
+                return Err(RecordError::Truncated);
+            }
+            let expected = u32_at(&header, UNCHECKED_HEADER_SIZE);
+            let actual = checksum(&header[4..UNCHECKED_HEADER_SIZE]);
+            if actual != expected {
+                return Err(RecordError::Checksum { expected, actual });
+            }
+            &header[..]
+        } else {
+            &header[..UNCHECKED_HEADER_SIZE]
+        };
+        let expected = u32_at(header, 0);
+        let record_type = header[4] & TYPE_MASK;
+        let encrypted = header[4] & ENCRYPTED != 0;
+        let compression_id = (header[4] >> 4) & COMPRESSION_MASK;
+        let compression = match Compression::from_id(compression_id) {
+            Some(compression) => compression,
+            None => {
+                return Err(RecordError::Invalid(format!(
+                    "unknown compression {}",
+                    compression_id
+                )))
+            }
+        };
+        let version = u64_at(header, 5);
+        let key_len = u64::from(u32_at(header, 13));
+        let value_len = u64::from(u32_at(header, 17));
+
+        // reading through take allocates as the bytes arrive, so a
+        // corrupted length doesn't make us allocate gigabytes
+        let mut payload = Vec::new();
+        reader
+            .take(key_len + value_len)
+            .read_to_end(&mut payload)
+            .map_err(RecordError::Io)?;
+        if (payload.len() as u64) < key_len + value_len {
+            return Err(RecordError::Truncated);
+        }
+
+        let mut hasher = Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&payload);
+        let actual = hasher.finalize();
+        if actual != expected {
+            return Err(RecordError::Checksum { expected, actual });
+        }
+
+        let (key, value) = if encrypted {
+            if key_len != 0 || record_type == TYPE_BATCH {
+                return Err(RecordError::Invalid(
+                    "unexpected encrypted record".to_owned(),
+                ));
+            }
+            let mut plaintext = keys.open(&header[4..UNCHECKED_HEADER_SIZE], &payload)?;
+            if plaintext.len() < 4 || plaintext.len() - 4 < u32_at(&plaintext, 0) as usize {
+                return Err(RecordError::Invalid("encrypted key too long".to_owned()));
+            }
+            let value = plaintext.split_off(4 + u32_at(&plaintext, 0) as usize);
+            (plaintext.split_off(4), value)
+        } else {
+            let value = payload.split_off(key_len as usize);
+            (payload, value)
+        };
+        if compression != Compression::None
+            && record_type != TYPE_SET
+            && record_type != TYPE_SET_EXPIRING
+        {
+            return Err(RecordError::Invalid(
+                "only values can be compressed".to_owned(),
+            ));
+        }
+        let cmd = match record_type {
+            TYPE_SET => Command::Set {
+                key,
+                value,
+                version,
+                expires_at: None,
+                compression,
+            },
+            TYPE_SET_EXPIRING if value.len() >= 8 => Command::Set {
+                key,
+                expires_at: Some(u64_at(&value, 0)),
+                value: value[8..].to_vec(),
+                version,
+                compression,
+            },
+            TYPE_SET_EXPIRING => {
+                return Err(RecordError::Invalid("expiring value too short".to_owned()))
+            }
+            TYPE_REMOVE => Command::Remove { key, version },
+            TYPE_BATCH => Command::Batch {
+                commands: decode_batch(&value, keys, header.len())?,
+            },
+            other => {
+                return Err(RecordError::Invalid(format!(
+                    "unknown record type {}",
+                    other
+                )))
+            }
+        };
+        let header_len = header.len() as u64;
+        let length = header_len + key_len + value_len;
+        Ok(Some(Record {
+            cmd,
+            length,
+            header_len,
+        }))
+    }
+
+    // the length of the record if it's not encrypted
+    fn encoded_len(&self, header_len: u64) -> u64 {
+        let payload = match self {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                ..
+            } => key.len() + value.len() + expires_at.map(|_| 8).unwrap_or(0),
+            Command::Remove { key, .. } => key.len(),
+            Command::Batch { commands } => {
+                return header_len
+                    + commands
+                        .iter()
+                        .map(|cmd| cmd.encoded_len(header_len))
+                        .sum::<u64>()
+            }
+        };
+        header_len + payload as u64
+    }
+
+    // the same command with the value compressed as given
+    pub fn recompress(self, to: Compression) -> Result<Command, String> {
+        match self {
+            Command::Set {
+                key,
+                value,
+                version,
+                expires_at,
+                compression,
+            } if compression != to => {
+                let (compression, value) = to.compress(compression.decompress(value)?);
+                Ok(Command::Set {
+                    key,
+                    value,
+                    version,
+                    expires_at,
+                    compression,
+                })
+            }
+            other => Ok(other),
+        }
+    }
+
+    // the single commands of the record that was just encoded
+    // to the offset along with their offsets and lengths. the
+    // commands of a batch are records of their own, so they can
+    // be read like any other
+    pub fn entries(&self, offset: u64, length: u64) -> Vec<(&Command, u64, u64)> {
+        self.entries_with(offset, length, HEADER_SIZE as u64)
+    }
+
+    // the commands of a batch have the same header as the batch
+    fn entries_with(&self, offset: u64, length: u64, header_len: u64) -> Vec<(&Command, u64, u64)> {
+        match self {
+            Command::Batch { commands } => {
+                // the commands of a batch are either all encrypted or
+                // none is, so encryption makes each longer by the same
+                let plain = commands
+                    .iter()
+                    .map(|cmd| cmd.encoded_len(header_len))
+                    .sum::<u64>();
+                let extra = (length.saturating_sub(header_len + plain))
+                    .checked_div(commands.len() as u64)
+                    .unwrap_or(0);
+                let mut offset = offset + header_len;
+                let mut entries = Vec::with_capacity(commands.len());
+                for cmd in commands {
+                    let length = cmd.encoded_len(header_len) + extra;
+                    entries.push((cmd, offset, length));
+                    offset += length;
+                }
+                entries
+            }
+            single => vec![(single, offset, length)],
+        }
+    }
+}
+
+impl Record {
+    // like Command::entries, but for the record at the offset
+    pub fn entries(&self, offset: u64) -> Vec<(&Command, u64, u64)> {
+        self.cmd.entries_with(offset, self.length, self.header_len)
+    }
+}
+
+fn decode_batch(
+    mut payload: &[u8],
+    keys: &Keyring,
+    header_len: usize,
+) -> Result<Vec<Command>, RecordError> {
+    let mut commands = Vec::new();
+    // the checksum of the batch was fine, so a broken
+    // command within it can't be a torn write
+    while let Some(record) = Command::decode(&mut payload, keys).map_err(|err| match err {
+        RecordError::Truncated => RecordError::Invalid("truncated command in batch".to_owned()),
+        other => other,
+    })? {
+        if let Command::Batch { .. } = record.cmd {
+            return Err(RecordError::Invalid("nested batch".to_owned()));
+        }
+        if record.header_len != header_len as u64 {
+            return Err(RecordError::Invalid(
+                "command header differs from batch".to_owned(),
+            ));
+        }
+        commands.push(record.cmd);
+    }
+    Ok(commands)
+}
+
+// a header with a placeholder for the checksum
+fn header(record_type: u8, version: u64, key_len: usize, value_len: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_SIZE + key_len + value_len);
+    buf.extend_from_slice(&[0; 4]);
+    buf.push(record_type | HEADER_CHECKED);
+    buf.extend_from_slice(&version.to_le_bytes());
+    buf.extend_from_slice(&(key_len as u32).to_le_bytes());
+    buf.extend_from_slice(&(value_len as u32).to_le_bytes());
+    let crc = checksum(&buf[4..]);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// the first version of the store wrote the commands as JSON
+// objects, one after another. a file of it starts with one
+pub fn is_json_log<R: Read>(reader: R) -> bool {
+    match serde_json::Deserializer::from_reader(reader)
+        .into_iter::<serde_json::Value>()
+        .next()
+    {
+        Some(Ok(serde_json::Value::Object(cmd))) => {
+            cmd.contains_key("Set") || cmd.contains_key("Remove")
+        }
+        _ => false,
+    }
+}
+
+// like read_exact, but tells us how far it got instead of
+// failing when the reader ends early
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, RecordError> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(RecordError::Io(e)),
+        }
+    }
+    Ok(read)
+}
@@ -0,0 +1,236 @@
+//! A storage engine backed by [sled](https://github.com/spacejam/sled)
+//!
+//! This exists to compare our own `KvStore` with an established
+//! embedded database behind the same interface.
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use sled::Tree;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::batch::{BatchOp, WriteBatch};
+use crate::engine::{KvError, KvsEngine, Result};
+use crate::meta;
+
+// must be increased whenever the stored values change incompatibly
+//  2: versions
+const FORMAT_VERSION: u32 = 2;
+
+/// A key value store that keeps its data in a sled database
+///
+/// # Examples
+///
+/// ```
+///  # use kvs::{KvsEngine, SledKvsEngine};
+///  # use tempfile::TempDir;
+///  # let dir = TempDir::new().unwrap();
+///  let kv = SledKvsEngine::open(dir.path()).unwrap();
+///  kv.set(String::from("foo"), String::from("bar")).unwrap();
+///  assert_eq!(Some(String::from("bar")), kv.get(String::from("foo")).unwrap());
+/// ```
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: sled::Db,
+    // the version of every key. it is updated along with the
+    // value in the same transaction
+    versions: Tree,
+}
+
+impl From<sled::Error> for KvError {
+    fn from(sled: sled::Error) -> KvError {
+        KvError::SledError { cause: sled }
+    }
+}
+
+impl SledKvsEngine {
+    /// Opens the sled database in the specified directory or
+    /// creates a new one if there is none. Fails if the directory
+    /// was written by a different engine.
+    pub fn open(dir: &Path) -> Result<SledKvsEngine> {
+        meta::check_or_init(dir, "sled", FORMAT_VERSION, false)?;
+        let db = sled::open(dir)?;
+        let versions = db.open_tree("versions")?;
+        Ok(SledKvsEngine { db, versions })
+    }
+
+    // sets the value or, if there is none, removes the key along
+    // with a new version. the check gets the current version and
+    // may abort the write. returns the new version
+    fn write<F>(&self, key: &[u8], value: Option<&[u8]>, check: F) -> Result<u64>
+    where
+        F: Fn(Option<u64>) -> Result<()>,
+    {
+        // sled never gives out the same id twice, even after a restart
+        let version = self.db.generate_id()? + 1;
+        let data: &Tree = &self.db;
+        (data, &self.versions)
+            .transaction(|(data, versions)| {
+                let current = match versions.get(key)? {
+                    Some(version) => Some(decode_version(&version)),
+                    // keys from before there were versions
+                    None => data.get(key)?.map(|_| 0),
+                };
+                check(current).map_err(ConflictableTransactionError::Abort)?;
+                match value {
+                    Some(value) => {
+                        data.insert(key, value)?;
+                        versions.insert(key, &version.to_be_bytes()[..])?;
+                    }
+                    None => {
+                        data.remove(key)?;
+                        versions.remove(key)?;
+                    }
+                }
+                Ok(version)
+            })
+            .map_err(from_transaction)
+    }
+}
+
+fn decode_version(bytes: &[u8]) -> u64 {
+    let mut version = [0; 8];
+    version.copy_from_slice(bytes);
+    u64::from_be_bytes(version)
+}
+
+fn from_transaction(err: TransactionError<KvError>) -> KvError {
+    match err {
+        TransactionError::Abort(err) => err,
+        TransactionError::Storage(err) => err.into(),
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(&key, Some(&value), |_| Ok(()))?;
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.write(&key, None, |current| match current {
+            None => Err(KvError::KeyNotFound),
+            Some(_) => Ok(()),
+        })?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        // all keys of the batch get the same version
+        let version = self.db.generate_id()? + 1;
+        let mut data_batch = sled::Batch::default();
+        let mut versions_batch = sled::Batch::default();
+        for op in batch.ops {
+            match op {
+                BatchOp::Put { key, value } => {
+                    versions_batch.insert(&key[..], &version.to_be_bytes()[..]);
+                    data_batch.insert(key, value);
+                }
+                BatchOp::Delete { key } => {
+                    versions_batch.remove(&key[..]);
+                    data_batch.remove(key);
+                }
+            }
+        }
+        let data: &Tree = &self.db;
+        (data, &self.versions)
+            .transaction(|(data, versions)| {
+                data.apply_batch(&data_batch)?;
+                versions.apply_batch(&versions_batch)?;
+                Ok(())
+            })
+            .map_err(from_transaction)
+    }
+
+    fn get_with_version(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        let data: &Tree = &self.db;
+        (data, &self.versions)
+            .transaction(|(data, versions)| {
+                let value = match data.get(&key)? {
+                    Some(value) => value,
+                    None => return Ok(None),
+                };
+                let version = versions.get(&key)?.map(|v| decode_version(&v));
+                Ok(Some((value.to_vec(), version.unwrap_or(0))))
+            })
+            .map_err(from_transaction)
+    }
+
+    fn compare_and_set(&self, key: Vec<u8>, expected: Option<u64>, value: Vec<u8>) -> Result<u64> {
+        self.write(&key, Some(&value), |current| {
+            if current == expected {
+                Ok(())
+            } else {
+                Err(KvError::VersionMismatch { current })
+            }
+        })
+    }
+
+    fn compare_and_remove(&self, key: Vec<u8>, expected: u64) -> Result<()> {
+        self.write(&key, None, |current| {
+            if current == Some(expected) {
+                Ok(())
+            } else {
+                Err(KvError::VersionMismatch { current })
+            }
+        })?;
+        Ok(())
+    }
+
+    // sled has no notion of expiry and emulating it would
+    // mean storing the deadline alongside every value
+    fn set_with_ttl(&self, _key: Vec<u8>, _value: Vec<u8>, _ttl: Duration) -> Result<()> {
+        Err(KvError::Unsupported("set_with_ttl".to_owned()))
+    }
+
+    fn ttl(&self, _key: Vec<u8>) -> Result<Option<Duration>> {
+        Err(KvError::Unsupported("ttl".to_owned()))
+    }
+
+    fn persist(&self, _key: Vec<u8>) -> Result<()> {
+        Err(KvError::Unsupported("persist".to_owned()))
+    }
+
+    fn checkpoint(&self, _target: &Path) -> Result<()> {
+        Err(KvError::Unsupported("checkpoint".to_owned()))
+    }
+
+    type Scan = SledScan;
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<SledScan> {
+        Ok(SledScan {
+            iter: self.db.range(range),
+            remaining: limit,
+        })
+    }
+}
+
+/// Iterator over a range of a `SledKvsEngine`
+pub struct SledScan {
+    iter: sled::Iter,
+    remaining: Option<usize>,
+}
+
+impl Iterator for SledScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == Some(0) {
+            return None;
+        }
+        self.remaining = self.remaining.map(|n| n - 1);
+        let pair = self.iter.next()?;
+        Some(
+            pair.map(|(key, value)| (key.to_vec(), value.to_vec()))
+                .map_err(KvError::from),
+        )
+    }
+}
@@ -0,0 +1,59 @@
+//! Statistics about a `KvStore`
+
+/// What `KvStore::stats` returns
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreStats {
+    /// The cache of values that were read
+    pub cache: CacheStats,
+    /// The memory that is needed to find the values
+    pub memory: MemoryStats,
+    /// The log files, oldest first
+    pub segments: Vec<SegmentStats>,
+}
+
+/// Statistics about the value cache
+#[derive(Clone, Debug, Default)]
+pub struct CacheStats {
+    /// Reads that were answered from the cache
+    pub hits: u64,
+    /// Reads that had to go to the file
+    pub misses: u64,
+    /// The number of values in the cache
+    pub entries: usize,
+    /// The size of the cached keys and values in bytes
+    pub bytes: usize,
+}
+
+/// Statistics about the memory the index takes up
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStats {
+    /// The number of keys in the index
+    pub keys: usize,
+    /// The size of all keys in bytes
+    pub key_bytes: usize,
+    /// The memory the keys are stored in. Keys are stored together,
+    /// so what is left of removed keys is only freed along with
+    /// the last key next to them
+    pub arena_bytes: usize,
+    /// An estimate of the size of the index in bytes,
+    /// including the keys
+    pub index_bytes: usize,
+    /// The number of log files that are open
+    pub segments: usize,
+    /// Replaced values that are kept for snapshots
+    pub history_entries: usize,
+}
+
+/// How much of a log file is still needed
+#[derive(Clone, Debug, Default)]
+pub struct SegmentStats {
+    /// The id of the segment. Only the files that were there at the
+    /// start have their counter as id, because a merged file takes
+    /// over the counter of the file it replaces but gets a new id
+    pub id: u32,
+    /// The length of the file
+    pub bytes: u64,
+    /// The bytes of the values the index points to. The rest
+    /// is freed by compacting the file
+    pub live_bytes: u64,
+}
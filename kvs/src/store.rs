@@ -1,34 +1,99 @@
 extern crate slog;
-extern crate slog_async;
-extern crate slog_term;
-use crate::slog::Drain;
-use serde::{Deserialize, Serialize};
+use crossbeam_skiplist::SkipMap;
+use crossbeam_utils::atomic::AtomicCell;
+use fs2::FileExt;
+use memmap2::Mmap;
 use serde_json;
 use slog::Logger;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::fs::{self, File, OpenOptions};
-use std::io;
-use std::io::Write;
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::io::{Seek, SeekFrom};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::engine::{KvError, KvsEngine, Result};
+use crate::arena::{ArenaKey, KeyArena};
+use crate::batch::{BatchOp, WriteBatch};
+use crate::cache::ValueCache;
+use crate::compactor::{CompactionTrigger, Compactor};
+use crate::compression::Compression;
+use crate::encryption::Keyring;
+use crate::engine::{self, KvError, KvsEngine, Result};
+use crate::hint::{self, Hint};
+use crate::meta::{self, Meta, PendingMerge};
+use crate::options::{Durability, KvStoreOptions};
+use crate::record::{self, Command, Record, RecordError};
+use crate::stats::{KvStoreStats, MemoryStats, SegmentStats};
+use crate::syncer::{Flusher, Syncer};
 
 /// A simple key value store
+///
+/// The store is cheap to clone and all clones share the
+/// same data, so it can be handed to as many threads as
+/// needed. Reads run in parallel, writes are serialized.
+#[derive(Clone)]
 pub struct KvStore {
+    // the index is a lock free map and the log files are
+    // read with positional reads, so readers never wait for
+    // each other. the pointers are too big to be replaced
+    // atomically, so a reader may wait for a writer that is
+    // replacing a pointer, see KeyDir
+    values: Arc<KeyDir>,
+    segments: Arc<Segments>,
+    // values of hot keys as of their current version
+    cache: Arc<ValueCache>,
+    history: Arc<History>,
+    snapshots: Arc<Mutex<Snapshots>>,
+    // held while compacted files are removed, so that a
+    // checkpoint can link all the files it has listed
+    removal: Arc<Mutex<()>>,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    compactor: Arc<Compactor>,
+    durability: Durability,
+    // the syncs are done outside of the writer, so
+    // other writes can proceed in the meantime
+    syncer: Arc<Syncer>,
+    // only set for Durability::Every. it's never used,
+    // but keeps the thread alive as long as the store
+    _flusher: Option<Arc<Flusher>>,
+    logger: Logger,
+    read_only: bool,
+    // the lock on the directory is released when the file is
+    // closed. it must come last, so that the background threads
+    // are stopped before another store can use the directory
+    _lock: Arc<Option<File>>,
+}
+
+// everything that is needed to modify the store. there
+// is only one of these per store and it's protected by
+// a mutex, so there is never more than one writer
+struct KvStoreWriter {
     db_dir: PathBuf,
-    // we keep two references to the active file,
-    // because to modify them (needed for appen), we
-    // need a RefCell, but in the values below, we
-    // need only the Rc (see ValuePointer). I didn't
-    // know how to convert the former to the latter
-    active_for_write: Rc<RefCell<File>>,
-    active_for_read: Rc<File>,
-    // number of values in the active file
-    active_entries: usize,
+    options: KvStoreOptions,
+    // the file we're appending to. readers get this
+    // through the segments. only a read only store
+    // may have none
+    active: Option<Arc<LogFile>>,
+    active_segment: u32,
+    // the id the next new segment gets. ids are never reused
+    next_segment: u64,
+    // the length of the active file in bytes
+    active_len: u64,
+    // the number of writes so far. it serves as ticket
+    // for waiting until a write is on disk
+    written: u64,
+    // the final state of the keys in the active file
+    // and its number of entries. this becomes the hint
+    // file when the active file is rotated
+    active_values: LogValues,
+    // the version of the next write. versions are shared by all
+    // keys, so the version of a key grows even across removes
+    next_version: u64,
     // current highest value of immutable files
     immutable_counter: u64,
     // number of immutable db files since last compaction
@@ -37,37 +102,525 @@ pub struct KvStore {
     // this is set to zero regardless of the number of
     // immutables
     immutables_since_last_compaction: usize,
+    // wakes up the compaction thread
+    compaction: CompactionTrigger,
+    // how much of each segment is still in use. compaction
+    // goes by it to pick the files worth rewriting
+    usage: HashMap<u32, SegmentUsage>,
+    // the 'Remove' commands that count as live in the usage
+    tombstones: Tombstones,
+    // files that were rotated or merged without a sync and
+    // whether their directory has changed since the last
+    // one. a flush catches up on them
+    unsynced: Vec<Arc<LogFile>>,
+    unsynced_dir: bool,
+    keys: Arc<Keyring>,
+    // the keys that are added to the index are copied into it
+    arena: KeyArena,
+
+    values: Arc<KeyDir>,
+    segments: Arc<Segments>,
+    cache: Arc<ValueCache>,
+    history: Arc<History>,
+    snapshots: Arc<Mutex<Snapshots>>,
+
+    logger: Logger,
+}
 
-    values: HashMap<String, ValuePointer>,
+// the index. the keys are stored in an arena. replacing an
+// entry of the skip list removes it before the new one is
+// there, so the pointers are changed in place instead. at 32
+// bytes, they are too big for the atomic instructions, so the
+// cell falls back to one of a fixed set of sequence locks,
+// picked by its address. reading through such a lock only
+// waits while a pointer that maps to the same lock is written
+type KeyDir = SkipMap<ArenaKey, AtomicCell<ValuePointer>>;
 
+// the log files by id. the files that are there at the start
+// have their counter as id, later ones get the next free id.
+// the counter of a file is only its position on disk, which
+// a merged file takes over from the one it replaces, but the
+// id never changes and is never reused. value pointers only
+// contain the id, so compacted files are removed from here
+// before they are deleted
+type Segments = SkipMap<u32, Arc<LogFile>>;
+
+// the state of a key right before the write with the version
+// in the key, or None if it didn't exist. a snapshot finds its
+// value in the oldest entry that came after it
+type History = SkipMap<(Vec<u8>, u64), Option<ValuePointer>>;
+
+// the sequence numbers of the snapshots that are in use and
+// how many there are of each. as long as there are any, the
+// writer keeps a history and compacted files are retained
+#[derive(Default)]
+struct Snapshots {
+    pinned: BTreeMap<u64, usize>,
+    // compacted files that snapshots may still read from
+    retained: Vec<(u32, PathBuf)>,
+}
+
+// the compaction runs on its own thread and only needs
+// the writer while the index is pointed to the merged
+// files that replace the ones about to be deleted
+struct Compaction {
+    writer: Arc<Mutex<KvStoreWriter>>,
+    values: Arc<KeyDir>,
+    segments: Arc<Segments>,
+    snapshots: Arc<Mutex<Snapshots>>,
+    removal: Arc<Mutex<()>>,
+    compression: Compression,
+    // set if all files must be rewritten, because the
+    // encryption key changed. cleared once that's done
+    rewrite: bool,
+    keys: Arc<Keyring>,
     logger: Logger,
 }
 
+#[derive(Clone, Copy)]
 struct ValueOffset(u64);
-struct Version(u64);
 
+#[derive(Clone, Copy, Default)]
+struct SegmentUsage {
+    // the length of the file
+    written: u64,
+    // the sizes of the commands the index points to, plus
+    // the tombstones compaction had to keep
+    live: u64,
+}
+
+// a log file along with its path, so errors
+// can tell which file is broken. the path is
+// updated when the active file is rotated.
+struct LogFile {
+    path: RwLock<PathBuf>,
+    file: File,
+    // set once the file is immutable. reading from the map
+    // takes neither a syscall nor a buffer
+    map: OnceLock<Mmap>,
+    // to decrypt the records
+    keys: Arc<Keyring>,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, file: File, keys: Arc<Keyring>) -> LogFile {
+        LogFile {
+            path: RwLock::new(path),
+            file,
+            map: OnceLock::new(),
+            keys,
+        }
+    }
+
+    // must only be called once nothing is appended to the file
+    // anymore. empty files can't be mapped, but there is
+    // nothing to read from them anyway
+    fn map(&self) -> io::Result<()> {
+        if self.file.metadata()?.len() > 0 {
+            // immutable files are never written to or truncated, and
+            // removing or renaming them leaves the map intact, so
+            // the mapped bytes never change underneath us
+            let map = unsafe { Mmap::map(&self.file)? };
+            let _ = self.map.set(map);
+        }
+        Ok(())
+    }
+
+    fn path(&self) -> PathBuf {
+        self.path.read().unwrap().clone()
+    }
+
+    // a reader starting at the offset. it doesn't use the
+    // cursor of the file, so any number of them can be
+    // used at the same time
+    fn reader_at(&self, offset: u64) -> LogReader<'_> {
+        match self.map.get() {
+            Some(map) => LogReader::Mapped(&map[cmp::min(offset, map.len() as u64) as usize..]),
+            None => LogReader::Positional(BufReader::new(PositionalReader {
+                file: &self.file,
+                offset,
+            })),
+        }
+    }
+
+    fn consistency_error(&self, offset: u64, err: RecordError) -> KvError {
+        let msg = format!(
+            "{} at offset {} in {}",
+            err,
+            offset,
+            self.path().to_string_lossy()
+        );
+        match err {
+            // the file is fine, we just can't read it
+            RecordError::UnknownKey(_) => KvError::WrongKey(msg),
+            _ => KvError::Consistency(msg),
+        }
+    }
+}
+
+impl fmt::Debug for LogFile {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.path().to_string_lossy())
+    }
+}
+
+enum LogReader<'a> {
+    Mapped(&'a [u8]),
+    Positional(BufReader<PositionalReader<'a>>),
+}
+
+impl<'a> Read for LogReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            LogReader::Mapped(bytes) => bytes.read(buf),
+            LogReader::Positional(reader) => reader.read(buf),
+        }
+    }
+}
+
+struct PositionalReader<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl<'a> Read for PositionalReader<'a> {
+    #[cfg(unix)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+        let n = self.file.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    #[cfg(windows)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::os::windows::fs::FileExt;
+        let n = self.file.seek_read(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Iterator over a range of a `KvStore`
+///
+/// Every step looks up the next key in the index, so the
+/// iterator doesn't block any reads or writes.
+pub struct KvStoreScan {
+    values: Arc<KeyDir>,
+    segments: Arc<Segments>,
+    // only set when iterating over a snapshot
+    snapshot: Option<Arc<Pinned>>,
+    // moves past every key that is returned
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    remaining: Option<usize>,
+}
+
+impl KvStoreScan {
+    // the smallest key in the range that is in the index or, for
+    // a snapshot, may have been replaced since it was taken
+    fn next_key(&self) -> Option<Vec<u8>> {
+        let range = (
+            self.start.as_ref().map(Vec::as_slice),
+            self.end.as_ref().map(Vec::as_slice),
+        );
+        let current = self
+            .values
+            .range::<[u8], _>(range)
+            .next()
+            .map(|entry| entry.key().to_vec());
+        let replaced = self.snapshot.as_ref().and_then(|snapshot| {
+            let start = match &self.start {
+                Bound::Included(key) => Bound::Included((key.clone(), 0)),
+                Bound::Excluded(key) => Bound::Excluded((key.clone(), u64::MAX)),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            let end = match &self.end {
+                Bound::Included(key) => Bound::Included((key.clone(), u64::MAX)),
+                Bound::Excluded(key) => Bound::Excluded((key.clone(), 0)),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            snapshot
+                .history
+                .range((start, end))
+                .next()
+                .map(|entry| entry.key().0.clone())
+        });
+        match (current, replaced) {
+            (Some(current), Some(replaced)) => Some(cmp::min(current, replaced)),
+            (current, replaced) => current.or(replaced),
+        }
+    }
+}
+
+impl Iterator for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == Some(0) {
+            return None;
+        }
+        loop {
+            let key = self.next_key()?;
+            self.start = Bound::Excluded(key.clone());
+            let value = match &self.snapshot {
+                Some(snapshot) => snapshot.read(&key),
+                None => KvStore::read_current(&self.values, &self.segments, &key)
+                    .map(|found| found.map(|(value, _)| value)),
+            };
+            // removed in the meantime or not part of the snapshot
+            let value = match value {
+                Ok(Some(value)) => Ok(value),
+                Ok(None) => continue,
+                Err(err) => Err(err),
+            };
+            self.remaining = self.remaining.map(|n| n - 1);
+            return Some(value.map(|value| (key, value)));
+        }
+    }
+}
+
+// there is one of these per key, so it's kept small
+#[derive(Clone, Copy)]
 struct ValuePointer {
-    file: Rc<File>,
-    offset: ValueOffset,
-    // the version based on which we can know whether
-    // the command in the file is outdated and can be
-    // cleaned up: we stoe the version here and in the
-    // command. when reading a command, we can compare
-    // it with this one and discard the command if that
-    // value is lower. the version is increased when ever
-    // we add a command by the same key (rm + set)
-    version: Version,
+    // the segment in the upper and the offset in the lower
+    // half. segments are rotated before they reach 4 GiB
+    location: u64,
+    // the version of the write that set the value. it's
+    // stored in the command as well, so it survives a restart.
+    // callers use it to detect concurrent modifications
+    version: u64,
+    // milliseconds since the epoch or NEVER
+    expires_at: u64,
+    // the length of the command in the file, so we know how
+    // much of the segment is freed when the value is replaced
+    size: u32,
+}
+
+const NEVER: u64 = u64::MAX;
+
+impl ValuePointer {
+    fn new(
+        segment: u32,
+        offset: ValueOffset,
+        size: u64,
+        version: u64,
+        expires_at: Option<u64>,
+    ) -> ValuePointer {
+        ValuePointer {
+            location: u64::from(segment) << 32 | offset.0,
+            version,
+            expires_at: expires_at.unwrap_or(NEVER),
+            // only a value that fills a segment on its own is that
+            // big, so all the accounting loses is that one segment
+            size: cmp::min(size, u64::from(u32::MAX)) as u32,
+        }
+    }
+
+    fn segment(&self) -> u32 {
+        (self.location >> 32) as u32
+    }
+
+    fn offset(&self) -> ValueOffset {
+        ValueOffset(self.location & u64::from(u32::MAX))
+    }
+
+    fn expires_at(&self) -> Option<u64> {
+        Some(self.expires_at).filter(|&at| at != NEVER)
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at <= now
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn current_pointer(values: &KeyDir, key: &[u8]) -> Option<ValuePointer> {
+    values.get(key).map(|entry| entry.value().load())
+}
+
+// the pointer of a key, unless there is none or it has expired
+fn live_pointer(values: &KeyDir, key: &[u8]) -> Option<ValuePointer> {
+    current_pointer(values, key).filter(|pointer| !pointer.is_expired(now_millis()))
+}
+
+/// A read-only view of a `KvStore` at one point in time
+///
+/// Writes that happen after the snapshot was taken are not
+/// visible through it, so reading several keys gives a
+/// consistent result. Values with a ttl still expire, though.
+///
+/// Compacted files are kept around until all snapshots are
+/// dropped, so they should not be held for longer than needed.
+#[derive(Clone)]
+pub struct KvStoreSnapshot {
+    pinned: Arc<Pinned>,
+}
+
+// unpins the sequence number once the last clone of a
+// snapshot and all of its scans are dropped
+struct Pinned {
+    sequence: u64,
+    values: Arc<KeyDir>,
+    segments: Arc<Segments>,
+    history: Arc<History>,
+    snapshots: Arc<Mutex<Snapshots>>,
+}
+
+impl Pinned {
+    fn lookup(&self, key: &[u8]) -> Option<ValuePointer> {
+        // the writer records the history before it changes the
+        // index, so whatever replaced the value we read from the
+        // index is in the history by the time we look
+        let current = current_pointer(&self.values, key);
+        let replaced = (
+            Bound::Excluded((key.to_vec(), self.sequence)),
+            Bound::Included((key.to_vec(), u64::MAX)),
+        );
+        match self.history.range(replaced).next() {
+            Some(entry) => *entry.value(),
+            None => current,
+        }
+        .filter(|pointer| !pointer.is_expired(now_millis()))
+    }
+
+    // the segments the history points to are retained, so
+    // unlike the current value, this one can't move away
+    fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.lookup(key) {
+            None => Ok(None),
+            Some(pointer) => KvStore::read_pointer(&self.segments, &pointer)?
+                .map(Some)
+                .ok_or_else(|| {
+                    KvError::Consistency(format!("Segment {} is gone", pointer.segment()))
+                }),
+        }
+    }
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if let Some(count) = snapshots.pinned.get_mut(&self.sequence) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.pinned.remove(&self.sequence);
+            }
+        }
+        match snapshots.pinned.keys().next() {
+            // the remaining snapshots only need what
+            // was replaced after the oldest of them
+            Some(&oldest) => {
+                for entry in self.history.iter() {
+                    if entry.key().1 <= oldest {
+                        entry.remove();
+                    }
+                }
+            }
+            None => {
+                self.history.clear();
+                // whatever is left is removed on the next start
+                for (segment, path) in snapshots.retained.drain(..) {
+                    self.segments.remove(&segment);
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+    }
+}
+
+impl KvStoreSnapshot {
+    /// The version of the last write that is visible
+    pub fn sequence(&self) -> u64 {
+        self.pinned.sequence
+    }
+
+    /// Returns the value of a key at the time of the snapshot
+    pub fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.pinned.read(&key)
+    }
+
+    /// Like `get_bytes`, but for text
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            None => Ok(None),
+            Some(value) => String::from_utf8(value)
+                .map(Some)
+                .map_err(|_| KvError::NotUtf8),
+        }
+    }
+
+    /// The version of the value of a key at the time of the snapshot
+    pub fn version(&self, key: Vec<u8>) -> Option<u64> {
+        self.pinned.lookup(&key).map(|pointer| pointer.version)
+    }
+
+    /// Returns how long the value of a key at the time of the snapshot
+    /// has left, or None if it doesn't expire
+    pub fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let now = now_millis();
+        match self.pinned.lookup(&key) {
+            Some(pointer) => Ok(pointer
+                .expires_at()
+                .map(|at| Duration::from_millis(at.saturating_sub(now)))),
+            None => Err(KvError::KeyNotFound),
+        }
+    }
+
+    /// Returns when the value of a key at the time of the snapshot
+    /// expires, or None if it doesn't. Unlike `ttl`, this doesn't
+    /// change while the snapshot is read
+    pub fn expires_at(&self, key: Vec<u8>) -> Result<Option<SystemTime>> {
+        match self.pinned.lookup(&key) {
+            Some(pointer) => Ok(pointer
+                .expires_at()
+                .map(|at| UNIX_EPOCH + Duration::from_millis(at))),
+            None => Err(KvError::KeyNotFound),
+        }
+    }
+
+    /// Returns the keys in the range along with their values at the
+    /// time of the snapshot, but no more than `limit` if there is one
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> KvStoreScan {
+        KvStoreScan {
+            values: self.pinned.values.clone(),
+            segments: self.pinned.segments.clone(),
+            snapshot: Some(self.pinned.clone()),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            remaining: limit,
+        }
+    }
+
+    /// Like `scan`, but for all keys that start with the prefix
+    pub fn scan_prefix(&self, prefix: Vec<u8>, limit: Option<usize>) -> KvStoreScan {
+        match engine::prefix_end(&prefix) {
+            Some(end) => self.scan(prefix..end, limit),
+            None => self.scan(prefix.., limit),
+        }
+    }
 }
 
 impl fmt::Display for KvStore {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "active_for_read:{:?}", self.active_for_read)?;
-        write!(fmt, "active_for_write:{:?}", self.active_for_write)?;
-        for (k, v) in &self.values {
+        if let Some(active) = &self.writer.lock().unwrap().active {
+            write!(fmt, "active:{:?}", active)?;
+        }
+        for entry in self.values.iter() {
+            let v = entry.value().load();
             write!(
                 fmt,
-                "{}: offset={}, version={}, file={:?}",
-                k, v.offset.0, v.version.0, v.file
+                "{}: offset={}, version={}, segment={}",
+                String::from_utf8_lossy(entry.key()),
+                v.offset().0,
+                v.version,
+                v.segment()
             )?;
         }
         Ok(())
@@ -86,180 +639,1164 @@ impl From<serde_json::error::Error> for KvError {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-enum Command {
-    Set {
-        key: String,
-        value: String,
-        version: u64,
-    },
-
-    Remove {
-        key: String,
-    },
-}
-
 // helper type for the function read_logs that returns information about a log file
 struct LogValues {
-    // the keys in this file (w/o the ones that were
-    // removed (a 'Set' command followed by an 'Remove' command)
-    values: HashMap<String, (ValueOffset, Version)>,
+    // the final state of the keys in this file. keys that were
+    // removed are kept as well, because they may have been set
+    // in an older file
+    values: HashMap<Vec<u8>, Hint>,
     // the number of elements in this file
     size: usize,
 }
 
-impl KvStore {
-    const ACTIVE_FILE_NAME: &'static str = "db.active";
+// a 'Remove' that hides the value of an older file. compactions
+// keep such a command, so it counts as live until the key is set
+// again or no older file has a value for it anymore
+struct Tombstone {
+    segment: u32,
+    size: u32,
+    // the newest of the older files with a value for the key
+    hides: u32,
+}
 
-    // after the active log file has reached this number
-    // of entries, we rotate the file. note that this is
-    // small (for now) for testing.
-    const FILE_ROTATION_TRESHOLD: usize = 150;
+// the last 'Remove' of keys that are gone, if it's a tombstone
+type Tombstones = HashMap<Vec<u8>, Tombstone>;
 
-    // after the number of files has reached this number,
-    // we run the compaction trying to reduce the number
-    // of files again
-    const COMPACTION_TRESHOLD: usize = 5;
+// what is built up while the files are read when opening
+#[derive(Default)]
+struct Loading {
+    values: Arc<KeyDir>,
+    arena: KeyArena,
+    tombstones: Tombstones,
+}
+
+impl KvStore {
+    const ACTIVE_FILE_NAME: &'static str = "db.active";
+    const LOCK_FILE_NAME: &'static str = "LOCK";
+    // must be increased whenever the files change incompatibly
+    //  2: batch records
+    //  3: expiring values
+    //  4: compressed values
+    //  5: encrypted records
+    //  6: header checksums
+    const FORMAT_VERSION: u32 = 6;
+    // what the skip list needs per entry besides the key and
+    // value: a reference count, the height and the pointers
+    // to the next entries, of which there are 1.33 on average
+    const INDEX_ENTRY_OVERHEAD: usize = 32;
+    // offsets in value pointers have 32 bits
+    const SEGMENT_LIMIT: u64 = 1 << 32;
 
     /// Creates a key value store in the specified directory
     ///
+    /// Fails with `KvError::WrongEngine` if the directory was
+    /// written by a different engine.
+    ///
     /// # Examples
     ///
     /// ```
-    ///  # use kvs::KvStore;
-    ///  # use std::path::Path;
-    ///  let mut kv = KvStore::open(Path::new("/tmp/"));
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path());
     /// ```
     pub fn open(dir: &Path) -> Result<KvStore> {
-        let (mut values, highest_counter) = KvStore::read_immutable_logs(&dir)?;
+        KvStore::open_with(dir, KvStoreOptions::default())
+    }
 
-        let active_path = dir.join(KvStore::ACTIVE_FILE_NAME);
-        let active_for_read = Rc::new(
-            // must be create+write or it will fail on the first call
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(&active_path)?,
-        );
-        let LogValues {
-            values: offsets,
-            size,
-        } = KvStore::read_log(&active_for_read)?;
-        for (key, (offset, version)) in offsets {
-            let active_for_read = active_for_read.clone();
-            values.insert(
-                key,
-                ValuePointer {
-                    file: active_for_read,
-                    offset,
-                    version,
-                },
-            );
+    /// Creates a key value store in the specified directory
+    /// using the specified options
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///  # use kvs::{KvStore, KvStoreOptions};
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let options = KvStoreOptions::default().compaction_trigger(10);
+    ///  let kv = KvStore::open_with(dir.path(), options);
+    /// ```
+    pub fn open_with(dir: &Path, options: KvStoreOptions) -> Result<KvStore> {
+        let logger = options.logger_or_default();
+        info!(logger, "initializing at {}", dir.to_string_lossy());
+        if options.durability == Durability::Every(Duration::from_secs(0)) {
+            return Err(KvError::InvalidOption(
+                "the interval of the durability must not be zero".to_owned(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&options.compaction_stale_ratio) {
+            return Err(KvError::InvalidOption(format!(
+                "the stale ratio of compactions must be between 0 and 1, not {}",
+                options.compaction_stale_ratio
+            )));
+        }
+        let read_only = options.read_only;
+        let lock = KvStore::lock_dir(dir, read_only)?;
+        let meta = meta::check_or_init(dir, "kvs", KvStore::FORMAT_VERSION, read_only)?;
+        let keys = Arc::new(Keyring::new(
+            options.encryption_key.as_ref(),
+            &options.old_encryption_keys,
+        ));
+        let rewrite = KvStore::check_key(dir, &meta, &keys, read_only)?
+            || !options.old_encryption_keys.is_empty();
+        if let Some(merge) = &meta.merge {
+            KvStore::finish_merge(dir, merge, read_only)?;
         }
+        if !read_only {
+            KvStore::remove_leftover_files(dir)?;
+        }
+
+        let mut loading = Loading::default();
+        let segments = Arc::new(SkipMap::new());
+        let (highest_counter, highest_version) =
+            KvStore::read_immutable_logs(dir, &mut loading, &segments, &keys, read_only, &logger)?;
 
-        let active_for_write = OpenOptions::new()
+        let active_path = dir.join(KvStore::ACTIVE_FILE_NAME);
+        // must be create+write or it will fail on the first call
+        let active = match OpenOptions::new()
             .read(true)
-            .write(true)
-            .create(true)
-            .open(&active_path)?;
-
-        let decorator = slog_term::TermDecorator::new().build();
-        let drain = slog_term::FullFormat::new(decorator).build().fuse();
-        let drain = slog_async::Async::new(drain).build().fuse();
-        let logger = slog::Logger::root(drain, o!("component" => "engine"));
-        info!(logger, "initializing at {}", dir.to_string_lossy());
+            .write(!read_only)
+            .create(!read_only)
+            .truncate(false)
+            .open(&active_path)
+        {
+            Ok(file) => Some(Arc::new(LogFile::open(active_path, file, keys.clone()))),
+            // checkpoints have no active file, and we must
+            // not create one if we don't write
+            Err(ref e) if read_only && e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let active_segment = KvStore::segment_id(highest_counter + 1)?;
+        let (active_values, active_len) = match &active {
+            Some(active) => {
+                segments.insert(active_segment, active.clone());
+                let values = KvStore::recover_active_log(active, read_only, &logger)?;
+                (values, active.file.metadata()?.len())
+            }
+            None => (
+                LogValues {
+                    values: HashMap::new(),
+                    size: 0,
+                },
+                0,
+            ),
+        };
+        let highest_version = cmp::max(
+            highest_version,
+            KvStore::apply_hints(&mut loading, active_segment, &active_values.values)?,
+        );
+        // the writes with the highest versions may have been
+        // compacted away, but not the ones in the metadata
+        let next_version = cmp::max(highest_version + 1, meta.next_version.unwrap_or(0));
+        let usage = KvStore::segment_usage(&loading, &segments)?;
+        let Loading {
+            values,
+            arena,
+            tombstones,
+        } = loading;
 
-        Ok(KvStore {
+        let durability = if read_only {
+            Durability::Never
+        } else {
+            options.durability
+        };
+        let cache = Arc::new(ValueCache::new(options.cache_capacity));
+        let history = Arc::new(SkipMap::new());
+        let snapshots = Arc::new(Mutex::new(Snapshots::default()));
+        let removal = Arc::new(Mutex::new(()));
+        let compression = options.compression;
+        let trigger = CompactionTrigger::default();
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
             db_dir: dir.to_owned(),
-            active_for_write: Rc::new(RefCell::new(active_for_write)),
-            active_for_read,
-            active_entries: size,
+            options,
+            active,
+            active_segment,
+            next_segment: u64::from(active_segment) + 1,
+            active_len,
+            written: 0,
+            active_values,
+            next_version,
             immutable_counter: highest_counter,
             immutables_since_last_compaction: 0,
+            compaction: trigger.clone(),
+            usage,
+            tombstones,
+            unsynced: Vec::new(),
+            unsynced_dir: false,
+            keys: keys.clone(),
+            arena,
+            values: values.clone(),
+            segments: segments.clone(),
+            cache: cache.clone(),
+            history: history.clone(),
+            snapshots: snapshots.clone(),
+            logger: logger.clone(),
+        }));
+
+        let mut compaction = Compaction {
+            writer: writer.clone(),
+            values: values.clone(),
+            segments: segments.clone(),
+            snapshots: snapshots.clone(),
+            removal: removal.clone(),
+            compression,
+            rewrite: rewrite && !read_only,
+            keys,
+            logger: logger.clone(),
+        };
+        // records with another key than the current one are
+        // rewritten by the compaction. the active file would
+        // be skipped, so it's rotated first
+        if rewrite && !read_only {
+            let mut writer = writer.lock().unwrap();
+            if writer.active_len > 0 {
+                writer.rotate()?;
+            }
+            trigger.trigger();
+        }
+        let compactor = Compactor::spawn(trigger, logger.clone(), move || {
+            if let Err(err) = compaction.run() {
+                error!(compaction.logger, "Compaction failed: {}", err);
+            }
+        });
+
+        let syncer = Arc::new(Syncer::default());
+        let flusher = match durability {
+            Durability::Every(interval) => {
+                let writer = writer.clone();
+                let syncer = syncer.clone();
+                let logger = logger.clone();
+                let flusher = Flusher::spawn(interval, move || {
+                    if let Err(err) = KvStore::sync_all_written(&writer, &syncer) {
+                        error!(logger, "Flushing failed: {}", err);
+                    }
+                });
+                Some(Arc::new(flusher))
+            }
+            _ => None,
+        };
+
+        Ok(KvStore {
             values,
+            segments,
+            cache,
+            history,
+            snapshots,
+            removal,
+            writer,
+            compactor: Arc::new(compactor),
+            durability,
+            syncer,
+            _flusher: flusher,
             logger,
+            read_only,
+            _lock: Arc::new(lock),
         })
     }
 
-    fn is_immutable_file(path: &Path) -> bool {
-        path.extension()
-            .map(|extension| extension.to_string_lossy() == "immutable")
-            .unwrap_or_else(|| false)
+    // takes an exclusive lock on the directory or a shared one
+    // if read only. this is an advisory lock, so it only keeps
+    // out other stores, but that's what we need. a read only
+    // store doesn't create the lock file, so if there is none
+    // yet, there is nothing to lock either
+    fn lock_dir(dir: &Path, read_only: bool) -> Result<Option<File>> {
+        let lock = match OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(!read_only)
+            .truncate(false)
+            .open(dir.join(KvStore::LOCK_FILE_NAME))
+        {
+            Ok(lock) => lock,
+            Err(ref e) if read_only && e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // called through the trait, because newer versions
+        // of std have inherent methods with the same name
+        let locked = if read_only {
+            FileExt::try_lock_shared(&lock)
+        } else {
+            FileExt::try_lock_exclusive(&lock)
+        };
+        match locked {
+            Ok(()) => Ok(Some(lock)),
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                Err(KvError::Locked(dir.to_owned()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // fails unless we have the keys the directory was encrypted with,
+    // either as the current one or an old one. the metadata is
+    // changed to the current key and true returned if the data
+    // has to be rewritten with it. the other keys stay in the
+    // metadata until the rewrite is done, so they are asked for
+    // again if we crash before that
+    fn check_key(dir: &Path, meta: &Meta, keys: &Keyring, read_only: bool) -> Result<bool> {
+        if let Some(check) = &meta.key_check {
+            if !keys.knows_check(check, true) {
+                let msg = if keys.is_encrypting() {
+                    "the directory was encrypted with a different key"
+                } else {
+                    "the directory is encrypted, but no key was given"
+                };
+                return Err(KvError::WrongKey(msg.to_owned()));
+            }
+        }
+        if meta
+            .old_key_checks
+            .iter()
+            .any(|check| !keys.knows_check(check, true))
+        {
+            return Err(KvError::WrongKey(
+                "some files are still encrypted with an old key that was not given".to_owned(),
+            ));
+        }
+        let current = keys.current_check();
+        if meta.key_check == current && meta.old_key_checks.is_empty() {
+            return Ok(false);
+        }
+        if !read_only {
+            let mut old = meta.old_key_checks.clone();
+            old.extend(meta.key_check.clone());
+            old.retain(|check| Some(check) != current.as_ref());
+            old.sort();
+            old.dedup();
+            meta::write_key_checks(dir, current, old)?;
+        }
+        Ok(true)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(KvError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    // waits until all writes up to the ticket are on disk,
+    // if the durability requires it
+    fn make_durable(&self, ticket: u64) -> Result<()> {
+        if self.durability == Durability::Always {
+            self.syncer
+                .sync_until(ticket, || KvStore::sync_active(&self.writer))?;
+        }
+        Ok(())
+    }
+
+    fn sync_all_written(writer: &Mutex<KvStoreWriter>, syncer: &Syncer) -> io::Result<()> {
+        let ticket = writer.lock().unwrap().written;
+        syncer.sync_until(ticket, || KvStore::sync_active(writer))
+    }
+
+    // syncs the files that were rotated or merged without a
+    // sync, so that along with the active one, all writes so
+    // far are on disk
+    fn sync_unsynced(writer: &Mutex<KvStoreWriter>) -> io::Result<()> {
+        let (files, dir) = {
+            let mut writer = writer.lock().unwrap();
+            let dir = mem::replace(&mut writer.unsynced_dir, false);
+            (mem::take(&mut writer.unsynced), dir)
+        };
+        for log in files {
+            log.file.sync_data()?;
+        }
+        if dir {
+            let dir = writer.lock().unwrap().db_dir.clone();
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // unless the durability is never, older files were synced
+    // when they were rotated, so syncing the active one covers
+    // all writes so far
+    fn sync_active(writer: &Mutex<KvStoreWriter>) -> io::Result<u64> {
+        let (written, active) = {
+            let writer = writer.lock().unwrap();
+            (writer.written, writer.active().clone())
+        };
+        active.file.sync_data()?;
+        Ok(written)
+    }
+
+    /// Stops starting new compactions until `resume_compaction`
+    /// is called. A compaction that is already running is finished.
+    pub fn pause_compaction(&self) {
+        self.compactor.pause();
+    }
+
+    /// Allows compactions to run again after `pause_compaction`
+    pub fn resume_compaction(&self) {
+        self.compactor.resume();
+    }
+
+    /// Blocks until all compactions that were triggered so far
+    /// are finished. While compaction is paused, this only waits
+    /// for the compaction that is currently running, if any.
+    pub fn wait_for_compaction(&self) {
+        self.compactor.wait();
+    }
+
+    /// Returns statistics about the store
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
+    ///  kv.set(String::from("foo"), String::from("bar")).unwrap();
+    ///  kv.get(String::from("foo")).unwrap();
+    ///  kv.get(String::from("foo")).unwrap();
+    ///  let stats = kv.stats();
+    ///  assert_eq!((1, 1), (stats.cache.hits, stats.cache.misses));
+    /// ```
+    ///
+    /// This walks over all keys to report the memory they
+    /// take up, so it shouldn't be called too often.
+    pub fn stats(&self) -> KvStoreStats {
+        let mut memory = MemoryStats::default();
+        for entry in self.values.iter() {
+            memory.keys += 1;
+            memory.key_bytes += entry.key().len();
+        }
+        let writer = self.writer.lock().unwrap();
+        memory.arena_bytes = writer.arena.allocated();
+        memory.index_bytes = memory.arena_bytes
+            + memory.keys
+                * (mem::size_of::<ArenaKey>()
+                    + mem::size_of::<AtomicCell<ValuePointer>>()
+                    + KvStore::INDEX_ENTRY_OVERHEAD);
+        memory.segments = self.segments.len();
+        memory.history_entries = self.history.len();
+        let mut segments: Vec<_> = writer
+            .usage
+            .iter()
+            .map(|(&id, usage)| SegmentStats {
+                id,
+                bytes: usage.written,
+                live_bytes: usage.live,
+            })
+            .collect();
+        segments.sort_by_key(|segment| segment.id);
+        KvStoreStats {
+            cache: self.cache.stats(),
+            memory,
+            segments,
+        }
+    }
+
+    // the lengths of the segments and how much of them the
+    // index points to
+    fn segment_usage(loading: &Loading, segments: &Segments) -> Result<HashMap<u32, SegmentUsage>> {
+        let mut usage = HashMap::new();
+        for entry in segments.iter() {
+            let written = entry.value().file.metadata()?.len();
+            usage.insert(*entry.key(), SegmentUsage { written, live: 0 });
+        }
+        for entry in loading.values.iter() {
+            let pointer = entry.value().load();
+            if let Some(usage) = usage.get_mut(&pointer.segment()) {
+                usage.live += u64::from(pointer.size);
+            }
+        }
+        for tombstone in loading.tombstones.values() {
+            if let Some(usage) = usage.get_mut(&tombstone.segment) {
+                usage.live += u64::from(tombstone.size);
+            }
+        }
+        Ok(usage)
+    }
+
+    // reads the current value of the key through the cache.
+    // snapshots and scans don't use it, so they don't push
+    // out the hot keys. returns the value and its version
+    fn read_cached(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        loop {
+            let pointer = match live_pointer(&self.values, key) {
+                None => return Ok(None),
+                Some(pointer) => pointer,
+            };
+            if let Some(value) = self.cache.get(key, pointer.version) {
+                return Ok(Some((value, pointer.version)));
+            }
+            let value = match KvStore::read_pointer(&self.segments, &pointer)? {
+                Some(value) => value,
+                None => continue,
+            };
+            self.cache.insert(key, pointer.version, &value, || {
+                current_pointer(&self.values, key)
+                    .map(|current| {
+                        current.location == pointer.location && current.version == pointer.version
+                    })
+                    .unwrap_or(false)
+            });
+            return Ok(Some((value, pointer.version)));
+        }
+    }
+
+    // reads the current value of the key along with its version.
+    // the value may be moved by a compaction while we read it, in
+    // which case its segment may be gone and we look again
+    fn read_current(
+        values: &KeyDir,
+        segments: &Segments,
+        key: &[u8],
+    ) -> Result<Option<(Vec<u8>, u64)>> {
+        loop {
+            let pointer = match live_pointer(values, key) {
+                None => return Ok(None),
+                Some(pointer) => pointer,
+            };
+            if let Some(value) = KvStore::read_pointer(segments, &pointer)? {
+                return Ok(Some((value, pointer.version)));
+            }
+        }
+    }
+
+    // None if the segment was compacted away
+    fn read_pointer(segments: &Segments, pointer: &ValuePointer) -> Result<Option<Vec<u8>>> {
+        match segments.get(&pointer.segment()) {
+            None => Ok(None),
+            Some(entry) => KvStore::read_at_offset(entry.value(), &pointer.offset()).map(Some),
+        }
+    }
+
+    // value pointers only have room for 32 bit segment ids
+    fn segment_id(counter: u64) -> Result<u32> {
+        if counter > u64::from(u32::MAX) {
+            return Err(KvError::Consistency(format!(
+                "Segment {} is beyond the highest possible one",
+                counter
+            )));
+        }
+        Ok(counter as u32)
+    }
+
+    /// Returns a read-only view of the store as it is right now
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
+    ///  kv.set(String::from("foo"), String::from("bar")).unwrap();
+    ///  let snapshot = kv.snapshot();
+    ///  kv.set(String::from("foo"), String::from("baz")).unwrap();
+    ///  assert_eq!(Some(String::from("bar")), snapshot.get(String::from("foo")).unwrap());
+    /// ```
+    pub fn snapshot(&self) -> KvStoreSnapshot {
+        // no write is in progress while we hold the writer, so
+        // each one is either part of the snapshot or recorded
+        // in the history
+        let writer = self.writer.lock().unwrap();
+        let sequence = writer.next_version - 1;
+        *self
+            .snapshots
+            .lock()
+            .unwrap()
+            .pinned
+            .entry(sequence)
+            .or_insert(0) += 1;
+        KvStoreSnapshot {
+            pinned: Arc::new(Pinned {
+                sequence,
+                values: self.values.clone(),
+                segments: self.segments.clone(),
+                history: self.history.clone(),
+                snapshots: self.snapshots.clone(),
+            }),
+        }
+    }
+
+    // links are only possible within the same file system
+    fn link_or_copy(path: &Path, target: &Path) -> Result<()> {
+        let name = path.file_name().ok_or_else(|| {
+            KvError::Consistency(format!("{} is not a file", path.to_string_lossy()))
+        })?;
+        let copy = target.join(name);
+        if fs::hard_link(path, &copy).is_err() {
+            fs::copy(path, &copy)?;
+        }
+        // the original may have never been synced
+        File::open(&copy)?.sync_all()?;
+        Ok(())
+    }
+
+    // files that were compacted while there were snapshots and
+    // merged files of a compaction that didn't get to replace its
+    // inputs. neither is needed anymore
+    fn remove_leftover_files(dir: &Path) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .map(|ext| ext == "retained" || ext == "merged")
+                .unwrap_or(false)
+            {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // the merged files were complete before the compaction was
+    // recorded, so all that is left is to replace the inputs
+    fn finish_merge(dir: &Path, merge: &PendingMerge, read_only: bool) -> Result<()> {
+        if read_only {
+            return Err(KvError::Consistency(
+                "A compaction was interrupted, it is finished by opening the store for writing"
+                    .to_owned(),
+            ));
+        }
+        KvStore::install_merge(dir, merge, true, |_, path| Ok(fs::remove_file(path)?))?;
+        meta::end_merge(dir)
+    }
+
+    // replaces the inputs of the merge with the merged files. the
+    // inputs are passed to discard. every step can be repeated,
+    // so this can be interrupted and started over
+    fn install_merge<F>(dir: &Path, merge: &PendingMerge, sync: bool, mut discard: F) -> Result<()>
+    where
+        F: FnMut(u64, &Path) -> Result<()>,
+    {
+        for &counter in &merge.inputs {
+            let path = dir.join(format!("{}.immutable", counter));
+            // the merged file already took the place of this one
+            if merge.outputs.contains(&counter) && !path.with_extension("merged").exists() {
+                continue;
+            }
+            // the hints must neither outlive the file nor
+            // be taken for those of the merged one
+            match fs::remove_file(hint::hint_path(&path)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                other => other?,
+            }
+            if path.exists() {
+                discard(counter, &path)?;
+            }
+        }
+        for &counter in &merge.outputs {
+            let merged = dir.join(format!("{}.merged", counter));
+            if merged.exists() {
+                fs::rename(&merged, merged.with_extension("immutable"))?;
+            }
+        }
+        if sync {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn is_immutable_file(path: &Path) -> bool {
+        path.extension()
+            .map(|extension| extension.to_string_lossy() == "immutable")
+            .unwrap_or_else(|| false)
+    }
+
+    // the immutable files in the order they were created
+    fn immutable_files(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+        let mut immutables = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if KvStore::is_immutable_file(&path) {
+                immutables.push((KvStore::extract_counter(&path)?, path));
+            }
+        }
+        immutables.sort();
+        Ok(immutables)
+    }
+
+    // the files must be read in the order they were created, so
+    // that newer commands replace older ones. if there is a hint
+    // file for an immutable file, we only read that one. returns
+    // the highest file counter and the highest version
+    fn read_immutable_logs(
+        dir: &Path,
+        loading: &mut Loading,
+        segments: &Segments,
+        keys: &Arc<Keyring>,
+        read_only: bool,
+        logger: &Logger,
+    ) -> Result<(u64, u64)> {
+        let mut highest_counter = 0;
+        let mut highest_version = 0;
+        for (counter, path) in KvStore::immutable_files(dir)? {
+            highest_counter = counter;
+
+            let file = Arc::new(LogFile::open(
+                path.clone(),
+                OpenOptions::new().read(true).open(&path)?,
+                keys.clone(),
+            ));
+            file.map()?;
+            let segment = KvStore::segment_id(counter)?;
+            segments.insert(segment, file.clone());
+            let version = match hint::read_hints(&path, keys)? {
+                Some(hints) => KvStore::apply_hints(loading, segment, &hints)?,
+                None => {
+                    info!(logger, "No valid hints for {}", path.to_string_lossy());
+                    let hints = KvStore::read_log(&file)?.values;
+                    if !read_only {
+                        hint::write_hints(&path, &hints, keys)?;
+                    }
+                    KvStore::apply_hints(loading, segment, &hints)?
+                }
+            };
+            highest_version = cmp::max(highest_version, version);
+        }
+        Ok((highest_counter, highest_version))
+    }
+
+    // returns the highest version in the hints
+    fn apply_hints(
+        loading: &mut Loading,
+        segment: u32,
+        hints: &HashMap<Vec<u8>, Hint>,
+    ) -> Result<u64> {
+        let Loading {
+            values,
+            arena,
+            tombstones,
+        } = loading;
+        let mut highest_version = 0;
+        let now = now_millis();
+        for (key, hint) in hints {
+            match hint {
+                Hint::Set {
+                    offset,
+                    version,
+                    size,
+                    expires_at,
+                } => {
+                    // files of older versions had no limit on their size
+                    if *offset >= KvStore::SEGMENT_LIMIT {
+                        return Err(KvError::Consistency(format!(
+                            "Offset {} in segment {} is beyond 4 GiB",
+                            offset, segment
+                        )));
+                    }
+                    let value_pointer = ValuePointer::new(
+                        segment,
+                        ValueOffset(*offset),
+                        u64::from(*size),
+                        *version,
+                        *expires_at,
+                    );
+                    tombstones.remove(key);
+                    // an expired value is as good as removed
+                    if value_pointer.is_expired(now) {
+                        values.remove(key.as_slice());
+                    } else if let Some(entry) = values.get(key.as_slice()) {
+                        // the key stays, so it isn't copied again
+                        entry.value().store(value_pointer);
+                    } else {
+                        values.insert(arena.alloc(key), AtomicCell::new(value_pointer));
+                    }
+                    highest_version = cmp::max(highest_version, *version);
+                }
+                Hint::Remove { version, size } => {
+                    // only needed while an older file has a value
+                    match values.remove(key.as_slice()) {
+                        Some(entry) => {
+                            let tombstone = Tombstone {
+                                segment,
+                                size: *size,
+                                hides: entry.value().load().segment(),
+                            };
+                            tombstones.insert(key.clone(), tombstone);
+                        }
+                        None => {
+                            tombstones.remove(key);
+                        }
+                    }
+                    highest_version = cmp::max(highest_version, *version);
+                }
+            }
+        }
+        Ok(highest_version)
+    }
+
+    fn extract_counter(path: &Path) -> Result<u64> {
+        match path.file_stem() {
+            None => Err(KvError::Consistency(format!(
+                "No file name: {}",
+                path.to_string_lossy()
+            ))),
+            Some(stem) => {
+                let stem = stem.to_string_lossy();
+                stem.parse::<u64>()
+                    .map_err(|_| KvError::Consistency(format!("Invalid file name: {}", stem)))
+            }
+        }
+    }
+
+    fn read_log(log: &LogFile) -> Result<LogValues> {
+        match KvStore::scan_log(log)? {
+            (values, None) => Ok(values),
+            (_, Some((offset, err))) => Err(log.consistency_error(offset, err)),
+        }
+    }
+
+    // if we crashed in the middle of appending to the active
+    // file, its last record is incomplete. such a record was
+    // never acknowledged, so it is safe to cut it off and
+    // continue with everything that was fully written. the
+    // header checksum tells such a record apart from one with
+    // a broken length, which is as corrupt as any other and
+    // must not take the records behind it along
+    //
+    // when opened read only, the broken record is just ignored
+    fn recover_active_log(log: &LogFile, read_only: bool, logger: &Logger) -> Result<LogValues> {
+        match KvStore::scan_log(log)? {
+            (values, None) => Ok(values),
+            (values, Some((offset, RecordError::Truncated))) if read_only => {
+                warn!(
+                    logger,
+                    "Ignoring a torn write at offset {} in {}",
+                    offset,
+                    log.path().to_string_lossy()
+                );
+                Ok(values)
+            }
+            (values, Some((offset, RecordError::Truncated))) => {
+                let length = log.file.metadata()?.len();
+                warn!(
+                    logger,
+                    "Discarding {} bytes of a torn write at offset {} in {}",
+                    length - offset,
+                    offset,
+                    log.path().to_string_lossy()
+                );
+                log.file.set_len(offset)?;
+                log.file.sync_all()?;
+                Ok(values)
+            }
+            (_, Some((offset, err))) => Err(log.consistency_error(offset, err)),
+        }
+    }
+
+    // reads all records of a log file until the end or the first
+    // broken record. in the latter case, the values up to that
+    // record are returned along with the reason and its offset
+    fn scan_log(log: &LogFile) -> Result<(LogValues, Option<(u64, RecordError)>)> {
+        let mut offset = 0;
+        let mut reader = log.reader_at(offset);
+        let mut values = HashMap::new();
+        let mut size = 0;
+        loop {
+            let record = match Command::decode(&mut reader, &log.keys) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(RecordError::Io(cause)) => return Err(KvError::IOError { cause }),
+                // not a broken record, but one we can't read
+                Err(_) if offset == 0 && record::is_json_log(log.reader_at(0)) => {
+                    return Err(KvError::WrongFormat {
+                        expected: KvStore::FORMAT_VERSION,
+                        found: 0,
+                    })
+                }
+                Err(err) => return Ok((LogValues { values, size }, Some((offset, err)))),
+            };
+            size += KvStore::add_hints(&mut values, record.entries(offset));
+            offset += record.length;
+        }
+        Ok((LogValues { values, size }, None))
+    }
+
+    // records the final state of the keys in the entries of a
+    // record and returns the number of commands in it
+    fn add_hints(hints: &mut HashMap<Vec<u8>, Hint>, entries: Vec<(&Command, u64, u64)>) -> usize {
+        for (cmd, offset, length) in &entries {
+            match cmd {
+                Command::Set {
+                    key,
+                    version,
+                    expires_at,
+                    ..
+                } => hints.insert(
+                    key.clone(),
+                    Hint::Set {
+                        offset: *offset,
+                        version: *version,
+                        size: *length as u32,
+                        expires_at: *expires_at,
+                    },
+                ),
+                Command::Remove { key, version } => hints.insert(
+                    key.clone(),
+                    Hint::Remove {
+                        version: *version,
+                        size: *length as u32,
+                    },
+                ),
+                Command::Batch { .. } => unreachable!("batches are never nested"),
+            };
+        }
+        entries.len()
+    }
+
+    fn read_at_offset(log: &LogFile, offset: &ValueOffset) -> Result<Vec<u8>> {
+        match Command::decode(&mut log.reader_at(offset.0), &log.keys) {
+            Ok(Some(Record {
+                cmd: Command::Set {
+                    value, compression, ..
+                },
+                ..
+            })) => compression
+                .decompress(value)
+                .map_err(|msg| log.consistency_error(offset.0, RecordError::Invalid(msg))),
+            Ok(_) => Err(KvError::Consistency(format!(
+                "No 'Set' command at offset {} in {}",
+                offset.0,
+                log.path().to_string_lossy()
+            ))),
+            Err(err) => Err(log.consistency_error(offset.0, err)),
+        }
+    }
+}
+
+impl KvStoreWriter {
+    // returns the version of the value
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<u64> {
+        let version = self.next_version;
+        self.set_at(key, value, version, expires_at)?;
+        Ok(version)
+    }
+
+    // like set, but with a version that was given out before, e.g.
+    // when restoring a backup. the history is still kept by the
+    // order of the writes, since that's what snapshots go by
+    fn set_at(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: u64,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let position = self.next_version;
+        let (compression, value) = self.options.compression.compress(value);
+        let cmd = Command::Set {
+            key: key.clone(),
+            value,
+            version,
+            expires_at,
+            compression,
+        };
+        let offset = self.append(&cmd)?;
+        self.next_version = cmp::max(position, version) + 1;
+        // append may rotate the active file, so this must happen after
+        let size = self.active_len - offset.0;
+        let value_pointer =
+            ValuePointer::new(self.active_segment, offset, size, version, expires_at);
+        self.record_history(&key, position);
+        self.update_index(&key, Some(value_pointer));
+        self.cache.invalidate(&key);
+        Ok(())
+    }
+
+    // writes the current value again without expiry
+    fn persist(&mut self, key: Vec<u8>) -> Result<()> {
+        // nothing is compacted while we hold the writer
+        let value = match live_pointer(&self.values, &key) {
+            None => return Err(KvError::KeyNotFound),
+            Some(pointer) if pointer.expires_at().is_none() => return Ok(()),
+            Some(pointer) => KvStore::read_pointer(&self.segments, &pointer)?.ok_or_else(|| {
+                KvError::Consistency(format!("Segment {} is gone", pointer.segment()))
+            })?,
+        };
+        self.set(key, value, None)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if live_pointer(&self.values, &key).is_none() {
+            return Err(KvError::KeyNotFound);
+        }
+        let version = self.next_version;
+        let cmd = Command::Remove {
+            key: key.clone(),
+            version,
+        };
+        self.append(&cmd)?;
+        self.next_version += 1;
+        self.record_history(&key, version);
+        self.update_index(&key, None);
+        self.cache.invalidate(&key);
+        Ok(())
+    }
+
+    // points the key to the value or removes it if there is none.
+    // the segments the old and new value are in are updated, so
+    // all changes to the index must go through here
+    fn update_index(&mut self, key: &[u8], pointer: Option<ValuePointer>) {
+        let entry = self.values.get(key);
+        if let Some(entry) = &entry {
+            let old = entry.value().load();
+            if let Some(usage) = self.usage.get_mut(&old.segment()) {
+                usage.live = usage.live.saturating_sub(u64::from(old.size));
+            }
+        }
+        if let Some(pointer) = pointer {
+            self.usage.entry(pointer.segment()).or_default().live += u64::from(pointer.size);
+        }
+        let inserted = entry.is_none() && pointer.is_some();
+        // only the writer changes the index, so the key
+        // can't be inserted by someone else in between
+        match (entry, pointer) {
+            (Some(entry), Some(pointer)) => entry.value().store(pointer),
+            (None, Some(pointer)) => {
+                let key = self.arena.alloc(key);
+                self.values.insert(key, AtomicCell::new(pointer));
+            }
+            (Some(entry), None) => {
+                entry.remove();
+            }
+            (None, None) => {}
+        }
+        // the value is newer than any 'Remove' of the key
+        if inserted {
+            self.drop_tombstone(key);
+        }
+    }
+
+    fn drop_tombstone(&mut self, key: &[u8]) {
+        if self.tombstones.is_empty() {
+            return;
+        }
+        if let Some(tombstone) = self.tombstones.remove(key) {
+            if let Some(usage) = self.usage.get_mut(&tombstone.segment) {
+                usage.live = usage.live.saturating_sub(u64::from(tombstone.size));
+            }
+        }
+    }
+
+    fn allocate_segment(&mut self) -> Result<u32> {
+        let segment = KvStore::segment_id(self.next_segment)?;
+        self.next_segment += 1;
+        Ok(segment)
     }
 
-    fn read_immutable_logs(dir: &Path) -> Result<(HashMap<String, ValuePointer>, u64)> {
-        let mut values = HashMap::new();
-        let mut highest_counter = 0;
-        for entry in fs::read_dir(&dir)? {
-            let path = entry?.path();
-            if KvStore::is_immutable_file(&path) {
-                let counter = KvStore::extract_counter(&path)?;
-                if counter > highest_counter {
-                    highest_counter = counter;
-                }
-
-                let file = Rc::new(OpenOptions::new().read(true).open(path)?);
-                for (key, (offset, version)) in KvStore::read_log(&file)?.values {
-                    let file = file.clone();
-                    values.insert(
-                        key,
-                        ValuePointer {
-                            file,
-                            offset,
-                            version,
-                        },
-                    );
-                }
+    // the bytes of the values that expired by segment. they are
+    // still in the index until a compaction drops them
+    fn expired_usage(&self) -> HashMap<u32, u64> {
+        let now = now_millis();
+        let mut expired = HashMap::new();
+        for entry in self.values.iter() {
+            let pointer = entry.value().load();
+            if pointer.is_expired(now) {
+                *expired.entry(pointer.segment()).or_insert(0) += u64::from(pointer.size);
             }
         }
-        Ok((values, highest_counter))
+        expired
     }
 
-    fn extract_counter(path: &Path) -> Result<u64> {
-        match path.file_stem() {
-            None => Err(KvError::Consistency(format!(
-                "No file name: {}",
-                path.to_string_lossy()
-            ))),
-            Some(stem) => {
-                let stem = stem.to_string_lossy();
-                stem.parse::<u64>()
-                    .map_err(|_| KvError::Consistency(format!("Invalid file name: {}", stem)))
+    // whether enough of the segment is stale to rewrite it,
+    // counting the values that expired in it as stale
+    fn is_worth_compacting(&self, segment: u32, expired: &HashMap<u32, u64>) -> bool {
+        match self.usage.get(&segment) {
+            None => false,
+            Some(usage) => {
+                let live = usage
+                    .live
+                    .saturating_sub(expired.get(&segment).copied().unwrap_or(0));
+                let stale = usage.written.saturating_sub(live);
+                stale > 0
+                    && stale as f64 >= usage.written as f64 * self.options.compaction_stale_ratio
             }
         }
     }
 
-    fn read_log(mut file: &File) -> Result<LogValues> {
-        let mut offset = file.seek(SeekFrom::Current(0))?;
-        let mut stream = serde_json::Deserializer::from_reader(file).into_iter::<Command>();
-        let mut values = HashMap::new();
-        let mut size = 0;
-        while let Some(cmd) = stream.next() {
-            match cmd? {
+    // keeps the state of the key right before the write with the
+    // version, unless there is no snapshot that could need it
+    fn record_history(&self, key: &[u8], version: u64) {
+        let snapshots = self.snapshots.lock().unwrap();
+        if !snapshots.pinned.is_empty() {
+            let before = current_pointer(&self.values, key);
+            self.history.insert((key.to_vec(), version), before);
+        }
+    }
+
+    // fails unless the key is at the expected version right now.
+    // None means that the key must not exist
+    fn check_version(&self, key: &[u8], expected: Option<u64>) -> Result<()> {
+        let current = live_pointer(&self.values, key).map(|pointer| pointer.version);
+        if current == expected {
+            Ok(())
+        } else {
+            Err(KvError::VersionMismatch { current })
+        }
+    }
+
+    // all changes of the batch are written as one record, so
+    // they are only applied to the index once all of them are
+    // in the file
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        // whether the keys exist as of the operations so far
+        let mut exists = HashMap::new();
+        let mut commands = Vec::with_capacity(batch.ops.len());
+        for op in batch.ops {
+            let version = self.next_version + commands.len() as u64;
+            match op {
+                BatchOp::Put { key, value } => {
+                    exists.insert(key.clone(), true);
+                    let (compression, value) = self.options.compression.compress(value);
+                    commands.push(Command::Set {
+                        key,
+                        value,
+                        version,
+                        expires_at: None,
+                        compression,
+                    });
+                }
+                BatchOp::Delete { key } => {
+                    let existed = match exists.get(&key) {
+                        Some(existed) => *existed,
+                        None => live_pointer(&self.values, &key).is_some(),
+                    };
+                    // deleting a key that doesn't exist is a no-op
+                    if existed {
+                        exists.insert(key.clone(), false);
+                        commands.push(Command::Remove { key, version });
+                    }
+                }
+            }
+        }
+        if commands.is_empty() {
+            return Ok(());
+        }
+        let count = commands.len() as u64;
+
+        let batch = Command::Batch { commands };
+        let offset = self.append(&batch)?;
+        self.next_version += count;
+        // append may rotate the active file, so this must happen after
+        let segment = self.active_segment;
+        let length = self.active_len - offset.0;
+        for (cmd, offset, size) in batch.entries(offset.0, length) {
+            match cmd {
                 Command::Set { key, version, .. } => {
-                    values.insert(key, (ValueOffset(offset), Version(version)))
+                    let value_pointer =
+                        ValuePointer::new(segment, ValueOffset(offset), size, *version, None);
+                    self.record_history(key, *version);
+                    self.update_index(key, Some(value_pointer));
+                    self.cache.invalidate(key);
                 }
-                Command::Remove { key } => values.remove(&key),
-            };
-            offset = stream.byte_offset() as u64;
-            size += 1;
-        }
-        Ok(LogValues { values, size })
-    }
-
-    fn read_at_offset(mut file: &File, offset: &ValueOffset) -> Result<String> {
-        file.seek(SeekFrom::Start(offset.0))?;
-        let maybe_cmd = serde_json::Deserializer::from_reader(file)
-            .into_iter::<Command>()
-            .next();
-        match maybe_cmd {
-            Some(Ok(Command::Set { value, .. })) => Ok(value),
-            _ => Err(KvError::Consistency(format!(
-                "No 'Set' command at offset {}",
-                offset.0
-            ))),
+                Command::Remove { key, version } => {
+                    self.record_history(key, *version);
+                    self.update_index(key, None);
+                    self.cache.invalidate(key);
+                }
+                Command::Batch { .. } => unreachable!("batches are never nested"),
+            }
         }
+        Ok(())
     }
 
     // rotates the active file by renaming the currently
@@ -267,136 +1804,611 @@ impl KvStore {
     // active file
     fn rotate(&mut self) -> Result<()> {
         info!(self.logger, "Rotating");
+        // the active file keeps its id, the new one gets the next
+        let next_segment = self.allocate_segment()?;
         self.immutable_counter += 1;
         self.immutables_since_last_compaction += 1;
         let immutable_file_path = self
             .db_dir
             .join(format!("{}.immutable", self.immutable_counter));
         let active_file_path = self.db_dir.join(KvStore::ACTIVE_FILE_NAME);
-        fs::rename(&active_file_path, immutable_file_path)?;
-
-        self.active_for_write = Rc::new(RefCell::new(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(&active_file_path)?,
-        ));
+        if self.options.durability != Durability::Never {
+            // writes waiting for a sync are on the new active
+            // file afterwards, so the old one is synced here
+            self.active().file.sync_data()?;
+        }
+        fs::rename(&active_file_path, &immutable_file_path)?;
+        hint::write_hints(&immutable_file_path, &self.active_values.values, &self.keys)?;
+        let active = self.active().clone();
+        *active.path.write().unwrap() = immutable_file_path;
+        // readers that already hold the file switch to the map
+        active.map()?;
+        if self.options.durability != Durability::Never {
+            // the rename must survive a crash as well
+            File::open(&self.db_dir)?.sync_all()?;
+        } else {
+            self.unsynced.push(active);
+            self.unsynced_dir = true;
+        }
 
-        self.active_for_read = Rc::new(
+        let active = Arc::new(LogFile::open(
+            active_file_path.clone(),
             OpenOptions::new()
                 .read(true)
                 .create(true)
                 .write(true)
+                .truncate(false)
                 .open(&active_file_path)?,
-        );
+            self.keys.clone(),
+        ));
+        self.segments.insert(next_segment, active.clone());
+        self.active = Some(active);
+        self.active_segment = next_segment;
 
-        self.active_entries = 0;
+        self.active_len = 0;
+        self.active_values = LogValues {
+            values: HashMap::new(),
+            size: 0,
+        };
         Ok(())
     }
 
+    // stores that write always have an active file
+    fn active(&self) -> &Arc<LogFile> {
+        self.active
+            .as_ref()
+            .expect("only read only stores have no active file")
+    }
+
+    fn should_compact(&self) -> bool {
+        self.immutables_since_last_compaction >= self.options.compaction_trigger
+    }
+
+    // the active file is rotated once it reaches either of the limits
+    fn should_rotate(&self) -> bool {
+        let too_many = self
+            .options
+            .segment_max_entries
+            .map(|max| self.active_values.size >= max)
+            .unwrap_or(false);
+        let too_big = self
+            .options
+            .segment_max_bytes
+            .map(|max| self.active_len >= max)
+            .unwrap_or(false);
+        too_many || too_big
+    }
+
+    fn append(&mut self, cmd: &Command) -> Result<ValueOffset> {
+        let bytes = cmd.encode(&self.keys);
+        // value pointers can't point beyond the limit
+        let too_big = self.active_len + bytes.len() as u64 > KvStore::SEGMENT_LIMIT;
+        if self.should_rotate() || (too_big && self.active_len > 0) {
+            self.rotate()?;
+            if self.should_compact() {
+                self.compaction.trigger();
+            }
+        }
+        let offset = {
+            // readers never touch the cursor, so it's ours alone
+            let mut active = &self.active().file;
+            let offset = ValueOffset(active.seek(SeekFrom::End(0))?);
+            if let Err(err) = active.write_all(&bytes) {
+                // whatever made it into the file must not be
+                // followed by the next record
+                active.set_len(offset.0)?;
+                return Err(err.into());
+            }
+            offset
+        };
+        self.active_len = offset.0 + bytes.len() as u64;
+        self.usage.entry(self.active_segment).or_default().written += bytes.len() as u64;
+        self.written += 1;
+        self.active_values.size += KvStore::add_hints(
+            &mut self.active_values.values,
+            cmd.entries(offset.0, bytes.len() as u64),
+        );
+        Ok(offset)
+    }
+}
+
+impl Compaction {
     // Compaction Algorithm
     //
-    // We iterate through the immutable files in order of
-    // their creation. Per file, we keep the active values
-    // ('Set' commands we encounter) in a map. If at least
-    // one value is dropped (ie. seen, but not part of the
-    // active ones), all active ones are appended and the
-    // file is unlinked.
+    // The writer keeps track of how many bytes of each immutable
+    // file the index still points to. A file is compacted once
+    // the rest, the stale part, makes up at least the configured
+    // ratio of it. The selected files are merged: the commands
+    // that are still needed are copied into new files, which are
+    // written off to the side while the store is used normally.
+    // Once they are complete, they are swapped in: the index is
+    // pointed to them and they replace the inputs on disk.
+    //
+    // The order of the files decides which command wins when the
+    // index is built on start, so a merged file takes the place
+    // of the newest input it contains. That's fine, because the
+    // files between its inputs have no command for the keys of
+    // the values that are copied, or those wouldn't be current.
+    // Merged files are only cut between inputs, so there is
+    // always a place for each.
     //
-    // Whenever we see a 'Remove' command, we can directly
-    // see this as a command to be dropped, because if there
-    // was a preceding 'Set', it would already have been
-    // added to the values to be discarded. Because we
-    // process files in the order of their creation, this
-    // even works across files, because 'Set' from previous
-    // files would have been dropped already.
+    // Files with fewer stale bytes are left alone, so they may
+    // still contain 'Set' commands that were replaced or removed
+    // later. That's why a 'Remove' can't always be dropped: if a
+    // file that is kept and older than the compacted one has a
+    // 'Set' for the key, the value would be back after a restart.
+    // So the keys of those files are collected and 'Remove'
+    // commands for them are copied, unless the key was set again.
+    // The same goes for values that expired, which are otherwise
+    // dropped without a 'Remove'.
     //
-    // This presupposes that we set the treshold of "unused"
-    // values to 0 though. If that was bigger, then we could
-    // have an unused 'Set' that was later not discarded,
-    // because it was the only obsolete command in a file,
-    // while the 'Remove' in the next file would be removed.
-    fn compact(&mut self) -> Result<()> {
+    // A value is only copied if the index points to exactly this
+    // command. Since a concurrent 'Set' or 'Remove' may replace
+    // it before the swap, this is checked again while holding
+    // the writer. The copy that was replaced stays in the merged
+    // file, but comes before the command that replaced it.
+    fn run(&mut self) -> Result<()> {
         info!(self.logger, "Compacting");
-        let mut immutables: Vec<PathBuf> = fs::read_dir(&self.db_dir)?
-            .filter_map(|p| p.ok())
-            .map(|e| e.path())
-            .filter(|p| KvStore::is_immutable_file(&p))
-            .collect();
-        immutables.sort();
-        for path in immutables {
-            self.compact_file(&path)?;
+        // the list is taken while holding the writer, so we
+        // never see a file that is in the middle of rotation
+        let (dir, limits, sync, immutables) = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.immutables_since_last_compaction = 0;
+            // the files may contain the highest versions so far. once
+            // they are gone, we still must not give them out again
+            meta::write_next_version(&writer.db_dir, writer.next_version)?;
+            let expired = writer.expired_usage();
+            let mut immutables = Vec::new();
+            for entry in self.segments.iter() {
+                let path = entry.value().path();
+                if KvStore::is_immutable_file(&path) {
+                    let segment = *entry.key();
+                    let selected = self.rewrite || writer.is_worth_compacting(segment, &expired);
+                    immutables.push((KvStore::extract_counter(&path)?, segment, path, selected));
+                }
+            }
+            immutables.sort();
+            let limits = (
+                writer.options.segment_max_entries,
+                writer.options.segment_max_bytes,
+            );
+            // the old keys are only forgotten once the files
+            // that were rewritten with the new one are on disk
+            let sync = writer.options.durability != Durability::Never || self.rewrite;
+            (writer.db_dir.clone(), limits, sync, immutables)
+        };
+        // the files after the last one that is compacted don't matter
+        let relevant = immutables
+            .iter()
+            .rposition(|(_, _, _, selected)| *selected)
+            .map_or(0, |last| last + 1);
+        // keys that files which are kept may still have a value
+        // for, along with the newest of those files
+        let mut older_keys = HashMap::new();
+        let mut merge = Merge::default();
+        let mut output: Option<MergeOutput> = None;
+        for (counter, segment, path, selected) in &immutables[..relevant] {
+            if !*selected {
+                for key in self.keys_with_values(*segment)? {
+                    older_keys.insert(key, *segment);
+                }
+                continue;
+            }
+            let length = fs::metadata(path)?.len();
+            let mut current = match output.take() {
+                Some(current) if !current.is_full(limits, length) => current,
+                Some(full) => {
+                    merge.add_output(full, sync)?;
+                    MergeOutput::create(&dir, *counter)?
+                }
+                None => MergeOutput::create(&dir, *counter)?,
+            };
+            self.merge_file(*segment, &older_keys, &mut current, &mut merge)?;
+            current.counter = *counter;
+            merge.inputs.push((*counter, *segment));
+            output = Some(current);
+        }
+        if let Some(last) = output {
+            merge.add_output(last, sync)?;
+        }
+        if !merge.inputs.is_empty() {
+            self.swap(&dir, merge, sync)?;
+        }
+        if self.rewrite {
+            // a checkpoint copies the checks along with the files
+            let _removal = self.removal.lock().unwrap();
+            meta::write_key_checks(&dir, self.keys.current_check(), Vec::new())?;
+            self.rewrite = false;
         }
-        self.immutables_since_last_compaction = 0;
         Ok(())
     }
 
-    fn compact_file(&mut self, path: &Path) -> Result<()> {
-        debug!(self.logger, "Compacting file {}", path.to_string_lossy());
-        let mut active_values = HashMap::new();
-        let mut inactive_amount = 0;
+    fn segment(&self, segment: u32) -> Result<Arc<LogFile>> {
+        self.segments
+            .get(&segment)
+            .map(|entry| entry.value().clone())
+            .ok_or_else(|| KvError::Consistency(format!("Segment {} is gone", segment)))
+    }
 
-        let file = OpenOptions::new().read(true).open(path)?;
-        let stream = serde_json::Deserializer::from_reader(file).into_iter::<Command>();
+    // the keys whose last command in the file is a 'Set'
+    fn keys_with_values(&self, segment: u32) -> Result<Vec<Vec<u8>>> {
+        let log = self.segment(segment)?;
+        let hints = match hint::read_hints(&log.path(), &self.keys)? {
+            Some(hints) => hints,
+            None => KvStore::read_log(&log)?.values,
+        };
+        Ok(hints
+            .into_iter()
+            .filter(|(_, hint)| match hint {
+                Hint::Set { .. } => true,
+                Hint::Remove { .. } => false,
+            })
+            .map(|(key, _)| key)
+            .collect())
+    }
 
-        for cmd in stream {
-            let cmd = cmd?;
-            match cmd {
-                Command::Set {
-                    ref key,
-                    ref version,
-                    ..
-                } => match self.values.get(key) {
-                    Some(value) if *version == value.version.0 => {
-                        debug!(self.logger, "Retaining {}, because version matches", key);
-                        active_values.insert(key.clone(), cmd);
+    fn is_current(&self, key: &[u8], segment: u32, offset: u64) -> bool {
+        match current_pointer(&self.values, key) {
+            Some(value) => value.segment() == segment && value.offset().0 == offset,
+            None => false,
+        }
+    }
+
+    // copies the commands of the file that are still needed
+    // into the merged file. older_keys are the keys of older
+    // files that are kept
+    fn merge_file(
+        &self,
+        segment: u32,
+        older_keys: &HashMap<Vec<u8>, u32>,
+        output: &mut MergeOutput,
+        merge: &mut Merge,
+    ) -> Result<()> {
+        let log = self.segment(segment)?;
+        debug!(self.logger, "Compacting file {:?}", log);
+        // 'Remove' commands that have to be kept
+        let mut removed = HashMap::new();
+        let mut inactive_amount = 0;
+        let mut copied = 0;
+        let now = now_millis();
+
+        let mut offset = 0;
+        let mut reader = log.reader_at(offset);
+        loop {
+            let record = match Command::decode(&mut reader, &self.keys) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(err) => return Err(log.consistency_error(offset, err)),
+            };
+            for (cmd, offset, _) in record.entries(offset) {
+                match cmd {
+                    Command::Set {
+                        key,
+                        version,
+                        expires_at: Some(at),
+                        ..
+                    } if *at <= now && self.is_current(key, segment, offset) => {
+                        merge.expired.push((key.clone(), segment, offset));
+                        if let Some(hides) = older_keys.get(key) {
+                            removed.insert(key.clone(), (*version, *hides));
+                        }
+                        inactive_amount += 1;
+                    }
+                    Command::Set { key, .. } if self.is_current(key, segment, offset) => {
+                        debug!(
+                            self.logger,
+                            "Retaining {}, because it is current",
+                            String::from_utf8_lossy(key)
+                        );
+                        // values are copied with the current compression
+                        let cmd = cmd.clone().recompress(self.compression).map_err(|msg| {
+                            log.consistency_error(offset, RecordError::Invalid(msg))
+                        })?;
+                        output.write(&cmd, &self.keys, Some((segment, offset)))?;
+                        copied += 1;
+                    }
+                    // unless the key was set again, the older
+                    // value must stay removed
+                    Command::Remove { key, version }
+                        if older_keys.contains_key(key)
+                            && self.values.get(key.as_slice()).is_none() =>
+                    {
+                        removed.insert(key.clone(), (*version, older_keys[key]));
+                        inactive_amount += 1;
+                    }
+                    // a 'Remove' of the key in a newer file may hide this
+                    // value. once it's gone, that one hides an older one
+                    // or nothing at all
+                    Command::Set { key, .. } if self.values.get(key.as_slice()).is_none() => {
+                        merge
+                            .unhidden
+                            .push((key.clone(), segment, older_keys.get(key).copied()));
+                        inactive_amount += 1;
                     }
                     _ => inactive_amount += 1,
-                },
-                Command::Remove { .. } => inactive_amount += 1,
+                };
+            }
+            offset += record.length;
+        }
+        debug!(
+            self.logger,
+            "Inactive amount: {}, values: {}, removes to keep: {}",
+            inactive_amount,
+            copied,
+            removed.len()
+        );
+        for (key, (version, hides)) in removed {
+            let cmd = Command::Remove {
+                key: key.clone(),
+                version,
             };
+            let size = output.write(&cmd, &self.keys, None)?;
+            output.tombstones.push((key, size, hides));
         }
+        Ok(())
+    }
 
-        if inactive_amount > 0 {
-            debug!(
-                self.logger,
-                "Inactive amount: {}, values: {}",
-                inactive_amount,
-                active_values.len()
-            );
-            for (_, cmd) in active_values {
-                self.append(&cmd, true)?;
+    // puts the merged files in place of the inputs. the merge is
+    // recorded in the metadata before any file is touched, so a
+    // crash in the middle of it is finished on the next start
+    fn swap(&self, dir: &Path, merge: Merge, sync: bool) -> Result<()> {
+        // a checkpoint must see either the inputs or the merged files
+        let _removal = self.removal.lock().unwrap();
+        let pending = PendingMerge {
+            inputs: merge.inputs.iter().map(|(counter, _)| *counter).collect(),
+            outputs: merge.outputs.iter().map(|output| output.counter).collect(),
+        };
+        meta::begin_merge(dir, &pending)?;
+        let mut installed = Vec::new();
+        {
+            let mut writer = self.writer.lock().unwrap();
+            for output in merge.outputs {
+                let segment = writer.allocate_segment()?;
+                let path = dir.join(format!("{}.immutable", output.counter));
+                let log = Arc::new(LogFile::open(
+                    path.clone(),
+                    File::open(&output.path)?,
+                    self.keys.clone(),
+                ));
+                log.map()?;
+                if !sync {
+                    writer.unsynced.push(log.clone());
+                }
+                self.segments.insert(segment, log);
+                // the 'Remove' commands were kept for a reason,
+                // so they aren't copied again by every compaction.
+                // unless the key was set again in the meantime
+                let mut live = 0;
+                for (key, size, hides) in output.tombstones {
+                    if self.values.get(key.as_slice()).is_some() {
+                        continue;
+                    }
+                    live += u64::from(size);
+                    let tombstone = Tombstone {
+                        segment,
+                        size,
+                        hides,
+                    };
+                    writer.tombstones.insert(key, tombstone);
+                }
+                writer.usage.insert(
+                    segment,
+                    SegmentUsage {
+                        written: output.len,
+                        live,
+                    },
+                );
+                for value in output.copied {
+                    let (from, old_offset) = value.from;
+                    if self.is_current(&value.key, from, old_offset) {
+                        let value_pointer = ValuePointer::new(
+                            segment,
+                            ValueOffset(value.offset),
+                            value.size,
+                            value.version,
+                            value.expires_at,
+                        );
+                        writer.update_index(&value.key, Some(value_pointer));
+                    }
+                }
+                installed.push((path, output.hints));
+            }
+            for (key, segment, offset) in merge.expired {
+                if self.is_current(&key, segment, offset) {
+                    debug!(
+                        self.logger,
+                        "Dropping {}, because it expired",
+                        String::from_utf8_lossy(&key)
+                    );
+                    writer.update_index(&key, None);
+                }
+            }
+            for (key, from, next) in merge.unhidden {
+                let hidden = match writer.tombstones.get_mut(&key) {
+                    Some(tombstone) if tombstone.hides == from => tombstone,
+                    _ => continue,
+                };
+                match next {
+                    Some(older) => hidden.hides = older,
+                    None => writer.drop_tombstone(&key),
+                }
+            }
+            // nothing points into the inputs anymore. the
+            // tombstones that were kept are in the merged files
+            let inputs: HashSet<u32> = merge.inputs.iter().map(|(_, segment)| *segment).collect();
+            writer
+                .tombstones
+                .retain(|_, tombstone| !inputs.contains(&tombstone.segment));
+            for segment in &inputs {
+                writer.usage.remove(segment);
+            }
+        }
+
+        let segments: HashMap<_, _> = merge.inputs.into_iter().collect();
+        KvStore::install_merge(dir, &pending, sync, |counter, path| {
+            self.discard(segments[&counter], path)
+        })?;
+        if !sync {
+            self.writer.lock().unwrap().unsynced_dir = true;
+        }
+        for (path, hints) in installed {
+            hint::write_hints(&path, &hints, &self.keys)?;
+        }
+        meta::end_merge(dir)
+    }
+
+    // snapshots may still read from a compacted file, so it is
+    // only renamed while there are any. the new name keeps it
+    // from being read again on the next start
+    fn discard(&self, segment: u32, path: &Path) -> Result<()> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if snapshots.pinned.is_empty() {
+            // readers that still have a pointer into
+            // the file look up the copied value instead
+            self.segments.remove(&segment);
+            fs::remove_file(path)?;
+        } else {
+            // a merged file may take the same place again later,
+            // so the segment makes the name unique
+            let retained = path.with_extension(format!("{}.retained", segment));
+            fs::rename(path, &retained)?;
+            if let Some(entry) = self.segments.get(&segment) {
+                *entry.value().path.write().unwrap() = retained.clone();
             }
-            fs::remove_file(&path)?;
+            snapshots.retained.push((segment, retained));
         }
+        Ok(())
+    }
+}
+
+// what a compaction has merged so far
+#[derive(Default)]
+struct Merge {
+    // counters and segments of the files that are replaced
+    inputs: Vec<(u64, u32)>,
+    outputs: Vec<MergeOutput>,
+    // values that are current, but dropped from the index
+    // instead of copied: key, segment and offset
+    expired: Vec<(Vec<u8>, u32, u64)>,
+    // values of removed keys that are dropped: key, segment and
+    // the newest file that is kept and has a value for the key
+    unhidden: Vec<(Vec<u8>, u32, Option<u32>)>,
+}
 
+impl Merge {
+    // a merged file that got no commands at all isn't installed,
+    // its inputs are simply removed. otherwise it would stay
+    // forever, since there's nothing stale in it to compact
+    fn add_output(&mut self, output: MergeOutput, sync: bool) -> Result<()> {
+        if output.len == 0 {
+            drop(output.file);
+            fs::remove_file(&output.path)?;
+        } else {
+            self.outputs.push(output.seal(sync)?);
+        }
         Ok(())
     }
+}
 
-    fn should_compact(&self) -> bool {
-        self.immutables_since_last_compaction >= KvStore::COMPACTION_TRESHOLD
+// a merged file. it's written as N.merged, where N is the
+// counter of the newest input so far, and renamed to
+// N.immutable once it takes the place of that input
+struct MergeOutput {
+    counter: u64,
+    path: PathBuf,
+    file: BufWriter<File>,
+    len: u64,
+    entries: usize,
+    hints: HashMap<Vec<u8>, Hint>,
+    copied: Vec<CopiedValue>,
+    // the 'Remove' commands that were kept: key, size and
+    // the file with the value they hide
+    tombstones: Vec<(Vec<u8>, u32, u32)>,
+}
+
+struct CopiedValue {
+    key: Vec<u8>,
+    // segment and offset of the original
+    from: (u32, u64),
+    offset: u64,
+    size: u64,
+    version: u64,
+    expires_at: Option<u64>,
+}
+
+impl MergeOutput {
+    fn create(dir: &Path, counter: u64) -> Result<MergeOutput> {
+        let path = dir.join(format!("{}.merged", counter));
+        Ok(MergeOutput {
+            counter,
+            file: BufWriter::new(File::create(&path)?),
+            path,
+            len: 0,
+            entries: 0,
+            hints: HashMap::new(),
+            copied: Vec::new(),
+            tombstones: Vec::new(),
+        })
     }
 
-    // no_compaction is used during compaction, when we append but we know that
-    // running compaction won't help (and could in fact introduce a loop)
-    fn append(&mut self, cmd: &Command, no_compaction: bool) -> Result<ValueOffset> {
-        if self.active_entries >= KvStore::FILE_ROTATION_TRESHOLD {
-            self.rotate()?;
+    // like the active file, a merged file ends once it reaches
+    // either of the limits. but it only ends between inputs and
+    // must not get too big for the value pointers with the next
+    fn is_full(&self, (max_entries, max_bytes): (Option<usize>, Option<u64>), next: u64) -> bool {
+        let too_many = max_entries.map(|max| self.entries >= max).unwrap_or(false);
+        let too_big = max_bytes.map(|max| self.len >= max).unwrap_or(false);
+        too_many || too_big || self.len + next > KvStore::SEGMENT_LIMIT
+    }
+
+    // from is where a copied value comes from. returns the
+    // size of the command
+    fn write(&mut self, cmd: &Command, keys: &Keyring, from: Option<(u32, u64)>) -> Result<u32> {
+        let bytes = cmd.encode(keys);
+        let length = bytes.len() as u64;
+        // only happens if the values got bigger when they were copied
+        if self.len + length > KvStore::SEGMENT_LIMIT {
+            return Err(KvError::Consistency(format!(
+                "{} would be larger than 4 GiB",
+                self.path.to_string_lossy()
+            )));
         }
-        if !no_compaction && self.should_compact() {
-            self.compact()?;
+        self.file.write_all(&bytes)?;
+        self.entries += KvStore::add_hints(&mut self.hints, cmd.entries(self.len, length));
+        if let (
+            Command::Set {
+                key,
+                version,
+                expires_at,
+                ..
+            },
+            Some(from),
+        ) = (cmd, from)
+        {
+            self.copied.push(CopiedValue {
+                key: key.clone(),
+                from,
+                offset: self.len,
+                size: length,
+                version: *version,
+                expires_at: *expires_at,
+            });
         }
-        let contents = serde_json::to_string(cmd)?;
-        let bytes = contents.as_bytes();
-        let offset = {
-            let mut active = self.active_for_write.borrow_mut();
-            let offset = ValueOffset(active.seek(SeekFrom::End(0))?);
-            active.write_all(bytes)?;
-            offset
-        };
-        self.active_entries += 1;
-        Ok(offset)
+        self.len += length;
+        Ok(length as u32)
+    }
+
+    // renames the file after the newest input it contains
+    fn seal(mut self, sync: bool) -> Result<MergeOutput> {
+        self.file.flush()?;
+        if sync {
+            // the copies must be on disk before
+            // the originals are deleted
+            self.file.get_ref().sync_data()?;
+        }
+        let path = self.path.with_file_name(format!("{}.merged", self.counter));
+        fs::rename(&self.path, &path)?;
+        self.path = path;
+        Ok(self)
     }
 }
 
@@ -406,80 +2418,386 @@ impl KvsEngine for KvStore {
     /// # Examples
     ///
     /// ```
-    ///  # use kvs::KvStore;
-    ///  # use std::path::Path;
-    ///  let mut kv = KvStore::open(Path::new("/tmp")).unwrap();
-    ///  kv.set(String::from("foo"), String::from("bar"));
-    ///  assert_eq!(Some(String::from("bar")), kv.get(String::from("foo")).unwrap());
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
+    ///  kv.set_bytes(vec![0xca, 0xfe], vec![0xff, 0x00]).unwrap();
+    ///  assert_eq!(Some(vec![0xff, 0x00]), kv.get_bytes(vec![0xca, 0xfe]).unwrap());
     /// ```
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        debug!(self.logger, "set({}, {})", key, value);
-        let version = self
-            .values
-            .get(&key)
-            .map(|v| v.version.0 + 1)
-            .unwrap_or_else(|| 0);
-        let cmd = Command::Set {
-            key: key.clone(),
-            value,
-            version,
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        debug!(
+            self.logger,
+            "set({}, {} bytes)",
+            String::from_utf8_lossy(&key),
+            value.len()
+        );
+        self.check_writable()?;
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.set(key, value, None)?;
+            writer.written
         };
-        let offset = self.append(&cmd, false)?;
-        // append modifies active_for_read, so this must happen after
-        let file = self.active_for_read.clone();
+        self.make_durable(ticket)
+    }
 
-        let value_pointer = ValuePointer {
-            file,
-            offset,
-            version: Version(version),
+    /// Returns the value associated with the specified key
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
+    ///  kv.set_bytes(vec![0xca, 0xfe], vec![0xff, 0x00]).unwrap();
+    ///  assert_eq!(Some(vec![0xff, 0x00]), kv.get_bytes(vec![0xca, 0xfe]).unwrap());
+    /// ```
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        debug!(self.logger, "get({})", String::from_utf8_lossy(&key));
+        Ok(self.read_cached(&key)?.map(|(value, _)| value))
+    }
+
+    /// Removes the value associated with the specified key
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
+    ///  kv.set_bytes(vec![0xca, 0xfe], vec![0xff, 0x00]).unwrap();
+    ///  kv.remove_bytes(vec![0xca, 0xfe]).unwrap();
+    ///  assert_eq!(None, kv.get_bytes(vec![0xca, 0xfe]).unwrap());
+    /// ```
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        debug!(self.logger, "remove({})", String::from_utf8_lossy(&key));
+        self.check_writable()?;
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.remove(key)?;
+            writer.written
         };
-        self.values.insert(key, value_pointer);
-        Ok(())
+        self.make_durable(ticket)
+    }
+    /// Applies all changes of the batch or, if it fails or
+    /// the process crashes in the middle of it, none of them
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///  # use kvs::{KvStore, KvsEngine, WriteBatch};
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
+    ///  kv.set(String::from("from"), String::from("10")).unwrap();
+    ///  let mut batch = WriteBatch::new();
+    ///  batch.delete(b"from".to_vec());
+    ///  batch.put(b"to".to_vec(), b"10".to_vec());
+    ///  kv.write_batch(batch).unwrap();
+    ///  assert_eq!(None, kv.get(String::from("from")).unwrap());
+    ///  assert_eq!(Some(String::from("10")), kv.get(String::from("to")).unwrap());
+    /// ```
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        debug!(self.logger, "write_batch({} operations)", batch.len());
+        self.check_writable()?;
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.write_batch(batch)?;
+            writer.written
+        };
+        self.make_durable(ticket)
     }
 
-    /// Returns the value associated with the specified key
+    /// Returns the value of a key along with its version
     ///
     /// # Examples
     ///
     /// ```
-    ///  # use kvs::KvStore;
-    ///  # use std::path::Path;
-    ///  let mut kv = KvStore::open(Path::new("/tmp")).unwrap();
-    ///  kv.set(String::from("foo"), String::from("bar"));
-    ///  assert_eq!(Some(String::from("bar")), kv.get(String::from("foo")).unwrap());
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use kvs::engine::KvError;
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
+    ///  kv.set(String::from("foo"), String::from("bar")).unwrap();
+    ///  let (_, version) = kv.get_with_version(b"foo".to_vec()).unwrap().unwrap();
+    ///  kv.compare_and_set(b"foo".to_vec(), Some(version), b"baz".to_vec()).unwrap();
+    ///  match kv.compare_and_set(b"foo".to_vec(), Some(version), b"qux".to_vec()) {
+    ///      Err(KvError::VersionMismatch { .. }) => {}
+    ///      other => panic!("unexpected {:?}", other),
+    ///  }
     /// ```
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        debug!(self.logger, "get({})", key);
-        match self.values.get(&key) {
-            None => Ok(None),
-            Some(ValuePointer { file, offset, .. }) => {
-                Ok(Some(KvStore::read_at_offset(file, offset)?))
+    fn get_with_version(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        debug!(
+            self.logger,
+            "get_with_version({})",
+            String::from_utf8_lossy(&key)
+        );
+        self.read_cached(&key)
+    }
+
+    /// Sets the value of a key if it's still at the expected version
+    fn compare_and_set(&self, key: Vec<u8>, expected: Option<u64>, value: Vec<u8>) -> Result<u64> {
+        debug!(
+            self.logger,
+            "compare_and_set({}, {:?}, {} bytes)",
+            String::from_utf8_lossy(&key),
+            expected,
+            value.len()
+        );
+        self.check_writable()?;
+        let (version, ticket) = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.check_version(&key, expected)?;
+            (writer.set(key, value, None)?, writer.written)
+        };
+        self.make_durable(ticket)?;
+        Ok(version)
+    }
+
+    /// Removes a key if it's still at the expected version
+    fn compare_and_remove(&self, key: Vec<u8>, expected: u64) -> Result<()> {
+        debug!(
+            self.logger,
+            "compare_and_remove({}, {})",
+            String::from_utf8_lossy(&key),
+            expected
+        );
+        self.check_writable()?;
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.check_version(&key, Some(expected))?;
+            writer.remove(key)?;
+            writer.written
+        };
+        self.make_durable(ticket)
+    }
+
+    /// Sets a value that expires after the ttl
+    ///
+    /// The expiry is stored in the log, so it survives a restart.
+    /// Expired values are dropped during compaction.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use std::time::Duration;
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
+    ///  kv.set_with_ttl(b"foo".to_vec(), b"bar".to_vec(), Duration::from_millis(10)).unwrap();
+    ///  assert!(kv.ttl(b"foo".to_vec()).unwrap().is_some());
+    ///  std::thread::sleep(Duration::from_millis(20));
+    ///  assert_eq!(None, kv.get_bytes(b"foo".to_vec()).unwrap());
+    /// ```
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        debug!(
+            self.logger,
+            "set_with_ttl({}, {} bytes, {:?})",
+            String::from_utf8_lossy(&key),
+            value.len(),
+            ttl
+        );
+        self.check_writable()?;
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.set(key, value, Some(expires_at))?;
+            writer.written
+        };
+        self.make_durable(ticket)
+    }
+
+    /// Returns how long the key has left
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let now = now_millis();
+        match current_pointer(&self.values, &key) {
+            Some(pointer) if !pointer.is_expired(now) => Ok(pointer
+                .expires_at()
+                .map(|at| Duration::from_millis(at - now))),
+            _ => Err(KvError::KeyNotFound),
+        }
+    }
+
+    /// Removes the ttl of a key
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use std::time::Duration;
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
+    ///  kv.set_with_ttl(b"foo".to_vec(), b"bar".to_vec(), Duration::from_secs(60)).unwrap();
+    ///  kv.persist(b"foo".to_vec()).unwrap();
+    ///  assert_eq!(None, kv.ttl(b"foo".to_vec()).unwrap());
+    /// ```
+    fn persist(&self, key: Vec<u8>) -> Result<()> {
+        debug!(self.logger, "persist({})", String::from_utf8_lossy(&key));
+        self.check_writable()?;
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.persist(key)?;
+            writer.written
+        };
+        self.make_durable(ticket)
+    }
+
+    /// Writes a copy of the store into the target directory, which
+    /// must be empty or not exist yet
+    ///
+    /// The active file is rotated first, so the copy contains all
+    /// writes so far and nothing but immutable files. These are
+    /// hard linked if possible, so a checkpoint is cheap. Writes
+    /// are only held up while the active file is rotated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  # let backup = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
+    ///  kv.set(String::from("foo"), String::from("bar")).unwrap();
+    ///  kv.checkpoint(&backup.path().join("copy")).unwrap();
+    ///  let copy = KvStore::open(&backup.path().join("copy")).unwrap();
+    ///  assert_eq!(Some(String::from("bar")), copy.get(String::from("foo")).unwrap());
+    /// ```
+    fn checkpoint(&self, target: &Path) -> Result<()> {
+        info!(self.logger, "checkpoint to {}", target.to_string_lossy());
+        self.check_writable()?;
+        fs::create_dir_all(target)?;
+        if fs::read_dir(target)?.next().is_some() {
+            return Err(KvError::IOError {
+                cause: io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is not empty", target.to_string_lossy()),
+                ),
+            });
+        }
+
+        // files must not be removed between listing and linking them
+        let _removal = self.removal.lock().unwrap();
+        let (immutables, next_version, key_check, old_key_checks) = {
+            let mut writer = self.writer.lock().unwrap();
+            if writer.active_len > 0 {
+                writer.rotate()?;
+            }
+            let immutables = KvStore::immutable_files(&writer.db_dir)?;
+            // files that weren't rewritten yet still need the old keys
+            let old_key_checks = meta::expect_meta(&writer.db_dir)?.old_key_checks;
+            (
+                immutables,
+                writer.next_version,
+                writer.keys.current_check(),
+                old_key_checks,
+            )
+        };
+
+        for (_, path) in immutables {
+            KvStore::link_or_copy(&path, target)?;
+            let hints = hint::hint_path(&path);
+            if hints.exists() {
+                KvStore::link_or_copy(&hints, target)?;
             }
         }
+        let meta = Meta {
+            engine: "kvs".to_owned(),
+            format_version: KvStore::FORMAT_VERSION,
+            next_version: Some(next_version),
+            key_check,
+            old_key_checks,
+            merge: None,
+        };
+        meta::write_meta(target, &meta)?;
+        File::open(target)?.sync_all()?;
+        Ok(())
     }
 
-    /// Removes the value associated with the specified key
+    /// Sets a value along with the version it had in a backup
+    fn restore(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: Option<u64>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<()> {
+        debug!(
+            self.logger,
+            "restore({}, {} bytes, {:?})",
+            String::from_utf8_lossy(&key),
+            value.len(),
+            version
+        );
+        self.check_writable()?;
+        let expires_at = expires_at.map(|at| {
+            at.duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or(0)
+        });
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            let version = version.unwrap_or(writer.next_version);
+            writer.set_at(key, value, version, expires_at)?;
+            writer.written
+        };
+        self.make_durable(ticket)
+    }
+
+    type Scan = KvStoreScan;
+
+    /// Returns the values in the range in key order
+    ///
+    /// # Examples
+    ///
+    /// ```
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
+    ///  kv.set(String::from("a"), String::from("1")).unwrap();
+    ///  kv.set(String::from("b"), String::from("2")).unwrap();
+    ///  kv.set(String::from("c"), String::from("3")).unwrap();
+    ///  let keys: Vec<Vec<u8>> = kv
+    ///      .scan(b"b".to_vec().., None)
+    ///      .unwrap()
+    ///      .map(|pair| pair.unwrap().0)
+    ///      .collect();
+    ///  assert_eq!(vec![b"b".to_vec(), b"c".to_vec()], keys);
+    /// ```
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<KvStoreScan> {
+        Ok(KvStoreScan {
+            values: self.values.clone(),
+            segments: self.segments.clone(),
+            snapshot: None,
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            remaining: limit,
+        })
+    }
+
+    /// Flushes all writes so far to disk, regardless of the durability
     ///
     /// # Examples
     ///
     /// ```
-    ///  # use kvs::KvStore;
-    ///  # use std::path::Path;
-    ///  let mut kv = KvStore::open(Path::new("/tmp")).unwrap();
+    ///  # use kvs::{KvStore, KvsEngine};
+    ///  # use tempfile::TempDir;
+    ///  # let dir = TempDir::new().unwrap();
+    ///  let kv = KvStore::open(dir.path()).unwrap();
     ///  kv.set(String::from("foo"), String::from("bar"));
-    ///  assert_eq!(Some(String::from("bar")), kv.get(String::from("foo")).unwrap());
-    ///  kv.remove(String::from("foo"));
-    ///  assert_eq!(None, kv.get(String::from("foo")).unwrap());
+    ///  kv.flush().unwrap();
     /// ```
-    fn remove(&mut self, key: String) -> Result<()> {
-        debug!(self.logger, "remove({})", key);
-        match self.values.remove(&key) {
-            None => Err(KvError::KeyNotFound),
-            Some(_) => {
-                let cmd = Command::Remove { key };
-                self.append(&cmd, false)?;
-                Ok(())
-            }
+    fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
         }
+        KvStore::sync_all_written(&self.writer, &self.syncer)?;
+        KvStore::sync_unsynced(&self.writer)?;
+        Ok(())
     }
 }
@@ -0,0 +1,103 @@
+// Group commit
+//
+// Every write gets a ticket, which is simply the number of
+// writes so far. A writer that needs its write on disk waits
+// until the synced ticket is at least its own. If no sync is
+// in progress, it does the sync itself, which then covers all
+// writes up to the latest one, not just its own. Writers that
+// arrive while a sync is in progress wait for it and, if it
+// didn't cover them, one of them starts the next one. This
+// way, many concurrent writers share a few syncs.
+use std::cmp;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[derive(Default)]
+struct State {
+    // all writes up to this ticket are on disk
+    synced: u64,
+    // somebody is syncing right now
+    syncing: bool,
+}
+
+#[derive(Default)]
+pub struct Syncer {
+    state: Mutex<State>,
+    cvar: Condvar,
+}
+
+impl Syncer {
+    // returns once the write with this ticket is on disk. the sync
+    // function must flush the data and return the latest ticket
+    // that is covered by the flush
+    pub fn sync_until<F>(&self, ticket: u64, sync: F) -> io::Result<()>
+    where
+        F: FnOnce() -> io::Result<u64>,
+    {
+        let mut sync = Some(sync);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.cvar.wait(state).unwrap();
+                continue;
+            }
+
+            state.syncing = true;
+            drop(state);
+            // we only get here once, because after our sync
+            // the synced ticket covers ours or we return the error
+            let result = (sync.take().unwrap())();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if let Ok(covered) = result {
+                state.synced = cmp::max(state.synced, covered);
+            }
+            self.cvar.notify_all();
+            result?;
+        }
+    }
+}
+
+// syncs periodically on a background thread. the thread
+// is stopped when this is dropped
+pub struct Flusher {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    pub fn spawn<F>(interval: Duration, mut flush: F) -> Flusher
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("kvs-flusher".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    flush();
+                }
+            })
+            .expect("failed to spawn flusher thread");
+        Flusher {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
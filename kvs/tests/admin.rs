@@ -0,0 +1,125 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine, Result, SledKvsEngine};
+use predicates::str::{contains, is_empty};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Exporting reads a snapshot and must not change the
+// directory or write anything but the entries
+#[test]
+fn export_is_read_only() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set_with_ttl(
+            b"key2".to_vec(),
+            b"value2".to_vec(),
+            Duration::from_secs(60),
+        )?;
+    }
+    let before = fs::read_dir(temp_dir.path())?.count();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--format", "jsonl", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains(r#""key":"key1","value":"value1""#))
+        .stdout(contains("expires_at"))
+        .stderr("Exported 2 entries\n");
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), before);
+
+    Ok(())
+}
+
+// Sled has no ttls, so the import stops at the first entry with
+// one. Entries are written as they are read, so the ones before
+// it are imported
+#[test]
+fn import_with_ttl_into_sled_stops() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let input = temp_dir.path().join("input.jsonl");
+    let dir = temp_dir.path().join("sled");
+    fs::create_dir(&dir)?;
+    fs::write(
+        &input,
+        "{\"key\":\"key1\",\"value\":\"value1\"}\n\
+         {\"key\":\"key2\",\"value\":\"value2\",\"expires_at\":99999999999999}\n\
+         {\"key\":\"key3\",\"value\":\"value3\"}\n",
+    )?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["import", "--engine", "sled", "--format", "jsonl", "--dir"])
+        .arg(&dir)
+        .arg("--input")
+        .arg(&input)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("ttl"));
+
+    let engine = SledKvsEngine::open(&dir)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+fn export(dir: &Path) -> Vec<u8> {
+    let output = Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["export", "--format", "jsonl", "--dir"])
+        .arg(dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    output.stdout
+}
+
+// The stored expiry is exported, not one computed from the
+// remaining ttl, so it doesn't move with every export
+#[test]
+fn expiry_does_not_drift() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let dir = temp_dir.path().join("kvs");
+    fs::create_dir(&dir)?;
+    let expires_at = {
+        let store = KvStore::open(&dir)?;
+        store.set_with_ttl(
+            b"key1".to_vec(),
+            b"value1".to_vec(),
+            Duration::from_secs(60),
+        )?;
+        store.snapshot().expires_at(b"key1".to_vec())?
+    };
+    assert!(expires_at.is_some());
+
+    let first = export(&dir);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(export(&dir), first);
+
+    let input = temp_dir.path().join("input.jsonl");
+    fs::write(&input, first)?;
+    let imported = temp_dir.path().join("imported");
+    fs::create_dir(&imported)?;
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["import", "--format", "jsonl", "--dir"])
+        .arg(&imported)
+        .arg("--input")
+        .arg(&input)
+        .assert()
+        .success();
+    let store = KvStore::open(&imported)?;
+    assert_eq!(store.snapshot().expires_at(b"key1".to_vec())?, expires_at);
+
+    Ok(())
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{client, start_server};
+use predicates::str::contains;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn backup(addr: &str, path: &str) -> Command {
+    client(addr, &["backup", path])
+}
+
+// Clients may only write below the backup directory
+#[test]
+fn backup_stays_in_backup_dir() {
+    let (temp_dir, backups) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let backup_dir = backups.path().join("backups");
+    let (_server, addr) = start_server(&temp_dir, &["--backup-dir", backup_dir.to_str().unwrap()]);
+
+    let outside = backups.path().join("outside");
+    for path in &[
+        outside.to_str().unwrap(),
+        "../outside",
+        "daily/../../outside",
+    ] {
+        backup(&addr, path)
+            .assert()
+            .failure()
+            .stderr(contains("must be relative"));
+    }
+    assert!(!outside.exists());
+
+    backup(&addr, "daily/1").assert().success();
+    assert!(backup_dir.join("daily/1/META").exists());
+}
+
+#[test]
+fn backup_needs_backup_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = start_server(&temp_dir, &[]);
+
+    backup(&addr, "daily")
+        .assert()
+        .failure()
+        .stderr(contains("--backup-dir"));
+    assert!(!temp_dir.path().join("daily").exists());
+}
@@ -0,0 +1,58 @@
+use kvs::engine::KvError;
+use kvs::{KvStore, KvsEngine, Result, SledKvsEngine};
+use tempfile::TempDir;
+
+// neither is valid UTF-8
+const KEY: &[u8] = b"key\xff\x00\xc3";
+const VALUE: &[u8] = b"\x80value\xfe\xff";
+
+fn assert_not_utf8<T: std::fmt::Debug>(result: Result<T>) {
+    match result {
+        Err(KvError::NotUtf8) => {}
+        other => panic!("expected a UTF-8 error, got {:?}", other),
+    }
+}
+
+// keys and values are just bytes, only reading them as text fails
+fn bytes_round_trip<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set_bytes(KEY.to_vec(), VALUE.to_vec())?;
+    assert_eq!(engine.get_bytes(KEY.to_vec())?, Some(VALUE.to_vec()));
+
+    engine.set_bytes(b"key".to_vec(), VALUE.to_vec())?;
+    assert_eq!(engine.get_bytes(b"key".to_vec())?, Some(VALUE.to_vec()));
+    assert_not_utf8(engine.get("key".to_owned()));
+
+    engine.remove_bytes(KEY.to_vec())?;
+    assert_eq!(engine.get_bytes(KEY.to_vec())?, None);
+    Ok(())
+}
+
+#[test]
+fn kvs_bytes_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    bytes_round_trip(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_bytes_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    bytes_round_trip(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn bytes_survive_reopen() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    KvStore::open(temp_dir.path())?.set_bytes(KEY.to_vec(), VALUE.to_vec())?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(KEY.to_vec())?, Some(VALUE.to_vec()));
+    assert_eq!(
+        store.snapshot().get_bytes(KEY.to_vec())?,
+        Some(VALUE.to_vec())
+    );
+
+    store.set_bytes(b"key".to_vec(), VALUE.to_vec())?;
+    assert_not_utf8(store.snapshot().get("key".to_owned()));
+
+    Ok(())
+}
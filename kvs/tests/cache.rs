@@ -0,0 +1,89 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, WriteBatch};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// reads the key twice, so its value is cached, and returns
+// the value along with the hits of the second read
+fn read_twice(store: &KvStore, key: &str) -> Result<(Option<String>, u64)> {
+    store.get(key.to_owned())?;
+    let hits = store.stats().cache.hits;
+    let value = store.get(key.to_owned())?;
+    Ok((value, store.stats().cache.hits - hits))
+}
+
+// Every kind of write replaces the cached value of its key
+#[test]
+fn writes_invalidate_the_cache() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(read_twice(&store, "key1")?, (Some("value1".to_owned()), 1));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(read_twice(&store, "key1")?, (Some("value2".to_owned()), 1));
+
+    let version = store.get_with_version(b"key1".to_vec())?.unwrap().1;
+    store.compare_and_set(b"key1".to_vec(), Some(version), b"value3".to_vec())?;
+    assert_eq!(read_twice(&store, "key1")?, (Some("value3".to_owned()), 1));
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"key1".to_vec(), b"value4".to_vec());
+    store.write_batch(batch)?;
+    assert_eq!(read_twice(&store, "key1")?, (Some("value4".to_owned()), 1));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.stats().cache.entries, 0);
+
+    // a value set again after the remove isn't the old one
+    store.set("key1".to_owned(), "value5".to_owned())?;
+    assert_eq!(read_twice(&store, "key1")?, (Some("value5".to_owned()), 1));
+
+    let mut batch = WriteBatch::new();
+    batch.delete(b"key1".to_vec());
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// A cached value that expired is not served from the cache
+#[test]
+fn expired_value_is_not_served() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(100);
+    store.set_with_ttl(b"key1".to_vec(), b"value1".to_vec(), ttl)?;
+    assert_eq!(read_twice(&store, "key1")?, (Some("value1".to_owned()), 1));
+
+    thread::sleep(ttl * 2);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Compaction moves values, but doesn't change them, so the
+// cached ones stay valid
+#[test]
+fn cache_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    // every rotation starts a compaction
+    let options = KvStoreOptions::default()
+        .segment_max_entries(2)
+        .compaction_trigger(1);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.wait_for_compaction();
+
+    assert_eq!(read_twice(&store, "key1")?, (Some("value1".to_owned()), 1));
+    assert_eq!(read_twice(&store, "key2")?, (Some("value3".to_owned()), 1));
+
+    Ok(())
+}
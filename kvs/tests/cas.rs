@@ -0,0 +1,138 @@
+use kvs::engine::KvError;
+use kvs::{KvStore, KvsEngine, Result, SledKvsEngine};
+use std::thread;
+use tempfile::TempDir;
+
+fn assert_mismatch<T: std::fmt::Debug>(result: Result<T>, current: Option<u64>) {
+    match result {
+        Err(KvError::VersionMismatch { current: c }) if c == current => {}
+        other => panic!(
+            "expected a version mismatch at {:?}, got {:?}",
+            current, other
+        ),
+    }
+}
+
+fn version<E: KvsEngine>(engine: &E, key: &[u8]) -> Result<Option<u64>> {
+    Ok(engine.get_with_version(key.to_vec())?.map(|(_, v)| v))
+}
+
+// a write with an outdated version fails and changes nothing
+fn conflicts<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = b"key1".to_vec();
+    let first = engine.compare_and_set(key.clone(), None, b"value1".to_vec())?;
+    assert_mismatch(
+        engine.compare_and_set(key.clone(), None, b"value2".to_vec()),
+        Some(first),
+    );
+
+    let second = engine.compare_and_set(key.clone(), Some(first), b"value2".to_vec())?;
+    assert!(second > first);
+    assert_mismatch(
+        engine.compare_and_set(key.clone(), Some(first), b"value3".to_vec()),
+        Some(second),
+    );
+    assert_mismatch(engine.compare_and_remove(key.clone(), first), Some(second));
+    assert_eq!(
+        engine.get_with_version(key.clone())?,
+        Some((b"value2".to_vec(), second))
+    );
+
+    // a plain write outdates the version as well
+    engine.set_bytes(key.clone(), b"value3".to_vec())?;
+    let third = version(&engine, &key)?.unwrap();
+    assert!(third > second);
+    assert_mismatch(engine.compare_and_remove(key.clone(), second), Some(third));
+
+    engine.compare_and_remove(key.clone(), third)?;
+    assert_eq!(engine.get_with_version(key.clone())?, None);
+    assert_mismatch(engine.compare_and_remove(key.clone(), third), None);
+    assert_mismatch(
+        engine.compare_and_set(key.clone(), Some(third), b"value4".to_vec()),
+        None,
+    );
+
+    // after a remove, the key starts over, but with a higher version
+    let fourth = engine.compare_and_set(key.clone(), None, b"value4".to_vec())?;
+    assert!(fourth > third);
+
+    Ok(())
+}
+
+// threads that increment a counter with compare_and_set
+// never lose an increment to another one
+fn concurrent_increments<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = b"counter".to_vec();
+    engine.compare_and_set(key.clone(), None, b"0".to_vec())?;
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            let key = key.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut increments = 0;
+                while increments < 50 {
+                    let (value, version) = engine.get_with_version(key.clone())?.unwrap();
+                    let count: u64 = String::from_utf8(value).unwrap().parse().unwrap();
+                    let next = (count + 1).to_string().into_bytes();
+                    match engine.compare_and_set(key.clone(), Some(version), next) {
+                        Ok(_) => increments += 1,
+                        Err(KvError::VersionMismatch { .. }) => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(engine.get_bytes(key)?, Some(b"200".to_vec()));
+
+    Ok(())
+}
+
+#[test]
+fn kvs_conflicts() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    conflicts(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_conflicts() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    conflicts(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn kvs_concurrent_increments() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    concurrent_increments(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_concurrent_increments() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    concurrent_increments(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// The versions must keep growing after a restart, or an old
+// version could match a new value
+#[test]
+fn versions_survive_reopen() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let key = b"key1".to_vec();
+    let before = {
+        let store = KvStore::open(temp_dir.path())?;
+        let version = store.compare_and_set(key.clone(), None, b"value1".to_vec())?;
+        store.remove_bytes(key.clone())?;
+        version
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    let after = store.compare_and_set(key.clone(), None, b"value2".to_vec())?;
+    assert!(after > before);
+    assert_mismatch(store.compare_and_remove(key, before), Some(after));
+
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use kvs::engine::KvError;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use tempfile::TempDir;
+
+// A checkpoint opens like any other directory, with the data
+// as of the checkpoint, and the two go separate ways after that
+#[test]
+fn checkpoint_is_opened() -> Result<()> {
+    let (temp_dir, backup) = (TempDir::new()?, TempDir::new()?);
+    let target = backup.path().join("checkpoint");
+    // small files, so the checkpoint has several of them
+    let options = || KvStoreOptions::default().segment_max_entries(3);
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "new value".to_owned())?;
+    let version = store.get_with_version(b"key1".to_vec())?.unwrap().1;
+    store.checkpoint(&target)?;
+    store.set("key2".to_owned(), "after".to_owned())?;
+
+    let copy = KvStore::open_with(&target, options())?;
+    assert_eq!(copy.get("key0".to_owned())?, None);
+    assert_eq!(copy.get("key1".to_owned())?, Some("new value".to_owned()));
+    assert_eq!(copy.get("key2".to_owned())?, Some("value".to_owned()));
+    assert_eq!(
+        copy.get_with_version(b"key1".to_vec())?,
+        Some((b"new value".to_vec(), version))
+    );
+
+    // the files may be shared, but the writes are not
+    for i in 0..10 {
+        copy.set(format!("key{}", i), "copy".to_owned())?;
+    }
+    assert!(copy.get_with_version(b"key1".to_vec())?.unwrap().1 > version);
+    assert_eq!(store.get("key3".to_owned())?, Some("value".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value".to_owned()));
+    assert_eq!(copy.get("key2".to_owned())?, Some("copy".to_owned()));
+
+    Ok(())
+}
+
+// Data in the target would be mixed with that of the checkpoint
+#[test]
+fn checkpoint_into_non_empty_directory_fails() -> Result<()> {
+    let (temp_dir, target) = (TempDir::new()?, TempDir::new()?);
+    fs::write(target.path().join("file"), b"content")?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match store.checkpoint(target.path()) {
+        Err(KvError::IOError { .. }) => {}
+        other => panic!("expected an error, got {:?}", other),
+    }
+    assert_eq!(fs::read_dir(target.path())?.count(), 1);
+
+    Ok(())
+}
+
+// Compactions in the store after the checkpoint must not
+// take away the files the checkpoint still needs
+#[test]
+fn checkpoint_survives_compaction() -> Result<()> {
+    let (temp_dir, backup) = (TempDir::new()?, TempDir::new()?);
+    let target = backup.path().join("checkpoint");
+    // every rotation starts a compaction
+    let options = KvStoreOptions::default()
+        .segment_max_entries(2)
+        .compaction_trigger(1);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "old".to_owned())?;
+    }
+    store.checkpoint(&target)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "new".to_owned())?;
+    }
+    store.wait_for_compaction();
+
+    let copy = KvStore::open(&target)?;
+    for i in 0..10 {
+        assert_eq!(copy.get(format!("key{}", i))?, Some("old".to_owned()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use kvs::engine::KvError;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+// one immutable file with two records of the same length, so the
+// second one starts in the middle. returns that offset
+fn write_file(dir: &Path) -> Result<u64> {
+    {
+        let options = KvStoreOptions::default()
+            .segment_max_entries(2)
+            .compaction_trigger(100);
+        let store = KvStore::open_with(dir, options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+    }
+    Ok(fs::metadata(dir.join("1.immutable"))?.len() / 2)
+}
+
+// flips a bit of the value of key2
+fn corrupt(dir: &Path) -> Result<()> {
+    let path = dir.join("1.immutable");
+    let mut bytes = fs::read(&path)?;
+    let at = bytes
+        .windows(6)
+        .position(|window| window == b"value2")
+        .unwrap();
+    bytes[at + 5] ^= 1;
+    fs::write(&path, bytes)?;
+    Ok(())
+}
+
+fn assert_corrupt<T>(result: Result<T>, offset: u64) {
+    match result {
+        Err(KvError::Consistency(msg)) => {
+            assert!(msg.contains("checksum"), "{}", msg);
+            assert!(msg.contains(&format!("offset {}", offset)), "{}", msg);
+            assert!(msg.contains("1.immutable"), "{}", msg);
+        }
+        Err(other) => panic!("expected a consistency error, got {:?}", other),
+        Ok(_) => panic!("expected a consistency error"),
+    }
+}
+
+// Without a hint, the whole file is read when the store is
+// opened, so it can't be opened at all
+#[test]
+fn corrupt_value_fails_open() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let offset = write_file(temp_dir.path())?;
+    corrupt(temp_dir.path())?;
+    fs::remove_file(temp_dir.path().join("1.hint"))?;
+
+    assert_corrupt(KvStore::open(temp_dir.path()), offset);
+
+    Ok(())
+}
+
+// With a hint, the file is only read along with the value,
+// and only that value fails
+#[test]
+fn corrupt_value_fails_get() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let offset = write_file(temp_dir.path())?;
+    corrupt(temp_dir.path())?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_corrupt(store.get("key2".to_owned()), offset);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{client, start_server};
+use predicates::str::contains;
+use std::process::Command;
+use tempfile::TempDir;
+
+// Both are refused before connecting, so no server is needed
+#[test]
+fn invalid_ttl_is_refused() {
+    for (ttl, message) in &[
+        ("18446744073709551615", "too long"),
+        ("0", "at least one second"),
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args([
+                "set",
+                "key1",
+                "value1",
+                "--addr",
+                "127.0.0.1:1",
+                "--ttl",
+                ttl,
+            ])
+            .assert()
+            .failure()
+            .stderr(contains(*message));
+    }
+}
+
+#[test]
+fn client_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = start_server(&temp_dir, &[]);
+    for (key, value) in &[("a", "1"), ("ab", "2"), ("abc", "3"), ("b", "4")] {
+        client(&addr, &["set", key, value]).assert().success();
+    }
+    client(&addr, &["rm", "abc"]).assert().success();
+
+    client(&addr, &["scan", "--prefix", "a"])
+        .assert()
+        .success()
+        .stdout("a 1\nab 2\n");
+    client(&addr, &["scan", "--start", "ab", "--end", "b"])
+        .assert()
+        .success()
+        .stdout("ab 2\n");
+    client(&addr, &["scan", "--limit", "2"])
+        .assert()
+        .success()
+        .stdout("a 1\nab 2\n");
+}
@@ -0,0 +1,51 @@
+// starting a server for the tests that go through kvs-client
+use assert_cmd::prelude::*;
+use std::net::TcpListener;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// kills the server when the test ends, even if it fails
+pub struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+pub fn client(addr: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(args).args(["--addr", addr]);
+    cmd
+}
+
+// starts the server and waits until it takes requests. that
+// is tried by removing a key, which nothing else writes to
+pub fn start_server(dir: &TempDir, args: &[&str]) -> (Server, String) {
+    let addr = free_addr();
+    let server = Server(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", &addr])
+            .args(args)
+            .current_dir(dir.path())
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        let output = client(&addr, &["rm", "starting"]).output().unwrap();
+        if String::from_utf8_lossy(&output.stderr).contains("Key not found") {
+            return (server, addr);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("the server did not start");
+}
@@ -0,0 +1,292 @@
+use kvs::engine::KvError;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// every rotation starts a compaction
+fn options() -> KvStoreOptions {
+    KvStoreOptions::default()
+        .segment_max_entries(2)
+        .compaction_trigger(1)
+}
+
+fn contains(dir: &Path, needle: &[u8]) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        let content = fs::read(entry?.path())?;
+        if content.windows(needle.len()).any(|window| window == needle) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// the ids of the files change when they are opened again
+fn usage(store: &KvStore) -> Vec<(u64, u64)> {
+    let mut usage: Vec<_> = store
+        .stats()
+        .segments
+        .iter()
+        .map(|segment| (segment.bytes, segment.live_bytes))
+        .collect();
+    usage.sort();
+    usage
+}
+
+// Nothing replaces or removes a value that expired, yet
+// its bytes are as stale as those of a removed one
+#[test]
+fn expired_values_are_reclaimed() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    let ttl = Duration::from_millis(50);
+    store.set_with_ttl(b"expiring0".to_vec(), b"value".to_vec(), ttl)?;
+    store.set_with_ttl(b"expiring1".to_vec(), b"value".to_vec(), ttl)?;
+    store.wait_for_compaction();
+    assert!(contains(temp_dir.path(), b"expiring")?);
+
+    thread::sleep(ttl * 2);
+    store.set("key0".to_owned(), "value".to_owned())?;
+    store.set("key1".to_owned(), "value".to_owned())?;
+    store.wait_for_compaction();
+    assert!(!contains(temp_dir.path(), b"expiring")?);
+    assert_eq!(store.get("expiring0".to_owned())?, None);
+
+    Ok(())
+}
+
+// A 'Remove' that a compaction kept is still needed after a
+// restart, so it must not make its file look stale then
+#[test]
+fn kept_removes_stay_live_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let removed = "a key that is long enough to outweigh the other".to_owned();
+    let before = {
+        let store = KvStore::open_with(temp_dir.path(), options())?;
+        store.set(removed.clone(), "value".to_owned())?;
+        store.set("big".to_owned(), "x".repeat(1000))?;
+        store.wait_for_compaction();
+        // the first file keeps its value for the key, so the
+        // 'Remove' is copied when the second one is compacted
+        store.remove(removed.clone())?;
+        store.set("small".to_owned(), "value".to_owned())?;
+        store.set("other".to_owned(), "value".to_owned())?;
+        store.wait_for_compaction();
+        usage(&store)
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    assert_eq!(usage(&store), before);
+    assert_eq!(store.get(removed)?, None);
+
+    Ok(())
+}
+
+// The writes go on while compactions swap files in the
+// background, and neither loses what the other did
+#[test]
+fn compaction_while_writing() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open_with(temp_dir.path(), options().segment_max_entries(10))?;
+    for key in 0..20 {
+        store.set(format!("key{}", key), "value0".to_owned())?;
+    }
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 1..100 {
+                for key in 0..20 {
+                    store.set(format!("key{}", key), format!("value{}", round))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    // reads of values whose files are compacted meanwhile
+    while !writer.is_finished() {
+        for key in 0..20 {
+            let value = store.get(format!("key{}", key))?;
+            assert!(value.unwrap().starts_with("value"));
+        }
+    }
+    writer.join().unwrap()?;
+    store.wait_for_compaction();
+
+    // a file for every ten writes, unless they were compacted
+    assert!(store.stats().segments.len() < 20);
+    for key in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key))?,
+            Some("value99".to_owned())
+        );
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key))?,
+            Some("value99".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Paused compactions let the files pile up until resumed
+#[test]
+fn paused_compaction_is_resumed() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    store.pause_compaction();
+    store.wait_for_compaction();
+    for round in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", round))?;
+        store.set("key2".to_owned(), format!("value{}", round))?;
+    }
+    let paused = store.stats().segments.len();
+    assert!(paused >= 9);
+
+    store.resume_compaction();
+    store.wait_for_compaction();
+    assert!(store.stats().segments.len() < paused);
+    assert_eq!(store.get("key1".to_owned())?, Some("value9".to_owned()));
+
+    Ok(())
+}
+
+// A 'Remove' is copied as long as an older file that is kept
+// may still have a value for the key, and dropped once not
+#[test]
+fn remove_is_dropped_with_the_older_value() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let removed = "removed key".to_owned();
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    store.set(removed.clone(), "value".to_owned())?;
+    store.set("big".to_owned(), "x".repeat(1000))?;
+    store.remove(removed.clone())?;
+    store.set("filler".to_owned(), "value1".to_owned())?;
+    // the second file is compacted, but the first one isn't
+    store.set("filler".to_owned(), "value2".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.wait_for_compaction();
+    assert!(contains(temp_dir.path(), removed.as_bytes())?);
+
+    // now the first file is compacted as well, so nothing that
+    // the 'Remove' hides is left and the next compaction drops it
+    store.set("big".to_owned(), "small".to_owned())?;
+    store.set("filler".to_owned(), "value3".to_owned())?;
+    store.set("other".to_owned(), "value2".to_owned())?;
+    store.wait_for_compaction();
+    store.set("other".to_owned(), "value3".to_owned())?;
+    store.set("filler".to_owned(), "value4".to_owned())?;
+    store.wait_for_compaction();
+    assert!(!contains(temp_dir.path(), removed.as_bytes())?);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    assert_eq!(store.get(removed)?, None);
+    assert_eq!(store.get("big".to_owned())?, Some("small".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn invalid_stale_ratio_is_rejected() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    for &ratio in &[f64::NAN, -0.1, 1.5, f64::INFINITY] {
+        let options = options().compaction_stale_ratio(ratio);
+        match KvStore::open_with(temp_dir.path(), options) {
+            Err(KvError::InvalidOption(_)) => {}
+            other => panic!(
+                "expected an option error for {}, got {:?}",
+                ratio,
+                other.map(|_| ())
+            ),
+        }
+    }
+    for &ratio in &[0.0, 1.0] {
+        KvStore::open_with(temp_dir.path(), options().compaction_stale_ratio(ratio))?;
+    }
+
+    Ok(())
+}
+
+// The active file is rotated once it is at least as long as the
+// limit, so no file is longer than that and one more command
+#[test]
+fn segments_are_rotated_by_size() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let options = KvStoreOptions::default()
+        .segment_max_entries(None)
+        .segment_max_bytes(1000);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.pause_compaction();
+    let value = "x".repeat(100);
+    for i in 0..50 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+
+    let segments = store.stats().segments;
+    // each file holds about ten commands
+    assert!(segments.len() >= 5, "{:?}", segments);
+    let (last, full) = segments.split_last().unwrap();
+    for segment in full {
+        assert!(segment.bytes >= 1000, "{:?}", segment);
+        assert!(segment.bytes < 1200, "{:?}", segment);
+    }
+    assert!(last.bytes < 1200, "{:?}", last);
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// writes a file of which half is stale and rotates twice more,
+// so the last compaction sees it stale
+fn half_stale_file(store: &KvStore) -> Result<()> {
+    store.set("key1".to_owned(), "overwritten".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.set("key1".to_owned(), "value".to_owned())?;
+    for i in 3..6 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.wait_for_compaction();
+    Ok(())
+}
+
+// With a ratio of 1, only files that are entirely stale qualify,
+// so a file that still has a live value is never rewritten
+#[test]
+fn ratio_one_keeps_files_with_live_values() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open_with(temp_dir.path(), options().compaction_stale_ratio(1.0))?;
+    half_stale_file(&store)?;
+    assert!(contains(temp_dir.path(), b"overwritten")?);
+
+    // the overwritten files are entirely stale and go
+    // away, but key2 keeps the first one live
+    for i in 0..10 {
+        store.set(format!("key{}", i % 3 + 3), "other".to_owned())?;
+    }
+    store.wait_for_compaction();
+    assert!(contains(temp_dir.path(), b"overwritten")?);
+    assert!(store.stats().segments.len() < 8);
+
+    Ok(())
+}
+
+// With a ratio of 0, a file is rewritten as soon as a single
+// byte of it is stale
+#[test]
+fn ratio_zero_compacts_any_stale_file() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open_with(temp_dir.path(), options().compaction_stale_ratio(0.0))?;
+    half_stale_file(&store)?;
+    assert!(!contains(temp_dir.path(), b"overwritten")?);
+    assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use kvs::{Compression, KvStore, KvStoreOptions, KvsEngine, Result};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn options(compression: Compression) -> KvStoreOptions {
+    KvStoreOptions::default()
+        .compression(compression)
+        .segment_max_entries(2)
+}
+
+// a value that compresses well, so it can't be found in the
+// files once it is compressed
+fn value(i: usize) -> String {
+    format!("value{} ", i).repeat(50)
+}
+
+fn contains(dir: &Path, needle: &[u8]) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        let content = fs::read(entry?.path())?;
+        if content.windows(needle.len()).any(|window| window == needle) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn remove_hints(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map(|ext| ext == "hint").unwrap_or(false) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// The compression can be changed at any time, since every
+// record says how its value is compressed
+#[test]
+fn compressions_are_mixed() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    {
+        let store = KvStore::open_with(temp_dir.path(), options(Compression::None))?;
+        for i in 0..5 {
+            store.set(format!("key{}", i), value(i))?;
+        }
+    }
+    {
+        let store = KvStore::open_with(temp_dir.path(), options(Compression::Lz4))?;
+        for i in 5..10 {
+            store.set(format!("key{}", i), value(i))?;
+        }
+        for i in 0..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+        }
+    }
+    assert!(contains(temp_dir.path(), value(0).as_bytes())?);
+    assert!(!contains(temp_dir.path(), value(5).as_bytes())?);
+
+    // the files are read as a whole without the hints
+    remove_hints(temp_dir.path())?;
+    let store = KvStore::open_with(temp_dir.path(), options(Compression::None))?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+
+    Ok(())
+}
+
+// Compaction copies the values with the current compression
+#[test]
+fn compaction_recompresses() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    {
+        let store = KvStore::open_with(temp_dir.path(), options(Compression::None))?;
+        for i in 0..3 {
+            store.set(format!("key{}", i), value(i))?;
+            store.set(format!("stale{}", i), "value".to_owned())?;
+        }
+    }
+    assert!(contains(temp_dir.path(), value(0).as_bytes())?);
+
+    {
+        // every rotation starts a compaction and every file
+        // with anything stale in it is compacted
+        let options = options(Compression::Lz4)
+            .compaction_trigger(1)
+            .compaction_stale_ratio(0.0);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for i in 0..3 {
+            store.remove(format!("stale{}", i))?;
+        }
+        store.set("other1".to_owned(), "value".to_owned())?;
+        store.set("other2".to_owned(), "value".to_owned())?;
+        store.wait_for_compaction();
+    }
+    for i in 0..3 {
+        assert!(!contains(temp_dir.path(), value(i).as_bytes())?);
+    }
+
+    // the merged files are read as a whole without the hints
+    remove_hints(temp_dir.path())?;
+    let store = KvStore::open_with(temp_dir.path(), options(Compression::None))?;
+    for i in 0..3 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+        assert_eq!(store.get(format!("stale{}", i))?, None);
+    }
+
+    Ok(())
+}
+
+// Values that don't get smaller are kept as they are
+#[test]
+fn incompressible_value_round_trips() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut rng = SmallRng::from_seed([1; 16]);
+    let random: Vec<u8> = (0..4096).map(|_| rng.gen()).collect();
+    let values = [random, b"x".to_vec(), Vec::new()];
+    {
+        let store = KvStore::open_with(temp_dir.path(), options(Compression::Lz4))?;
+        for (i, value) in values.iter().enumerate() {
+            store.set_bytes(format!("key{}", i).into_bytes(), value.clone())?;
+            assert_eq!(
+                store.get_bytes(format!("key{}", i).into_bytes())?,
+                Some(value.clone())
+            );
+        }
+    }
+    assert!(contains(temp_dir.path(), &values[0])?);
+
+    let store = KvStore::open_with(temp_dir.path(), options(Compression::Lz4))?;
+    for (i, value) in values.iter().enumerate() {
+        assert_eq!(
+            store.get_bytes(format!("key{}", i).into_bytes())?,
+            Some(value.clone())
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tempfile::TempDir;
+
+const WRITERS: usize = 4;
+const READERS: usize = 4;
+const KEYS: usize = 10;
+const ROUNDS: usize = 200;
+
+fn key(writer: usize, key: usize) -> String {
+    format!("writer{}-key{}", writer, key)
+}
+
+// Every writer counts its own keys up, while readers check that
+// no value is ever torn or older than one they saw before
+#[test]
+fn concurrent_get_and_set() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    // small files, so the readers also see rotations and compactions
+    let options = KvStoreOptions::default()
+        .segment_max_entries(50)
+        .compaction_trigger(2);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let done = Arc::new(AtomicBool::new(false));
+
+    let readers: Vec<_> = (0..READERS)
+        .map(|_| {
+            let store = store.clone();
+            let done = done.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut seen = vec![vec![0; KEYS]; WRITERS];
+                while !done.load(Ordering::SeqCst) {
+                    for (writer, seen) in seen.iter_mut().enumerate() {
+                        for (k, seen) in seen.iter_mut().enumerate() {
+                            if let Some(value) = store.get(key(writer, k))? {
+                                let round: usize = value.parse().expect("a torn value");
+                                assert!(round >= *seen, "value went back");
+                                *seen = round;
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|writer| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for round in 1..=ROUNDS {
+                    for k in 0..KEYS {
+                        store.set(key(writer, k), round.to_string())?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    for writer in 0..WRITERS {
+        for k in 0..KEYS {
+            assert_eq!(store.get(key(writer, k))?, Some(ROUNDS.to_string()));
+        }
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for writer in 0..WRITERS {
+        for k in 0..KEYS {
+            assert_eq!(store.get(key(writer, k))?, Some(ROUNDS.to_string()));
+        }
+    }
+
+    Ok(())
+}
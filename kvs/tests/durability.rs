@@ -0,0 +1,48 @@
+use kvs::engine::KvError;
+use kvs::{Durability, KvStore, KvStoreOptions, KvsEngine, Result};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A flusher without a pause would never stop syncing
+#[test]
+fn zero_interval_is_rejected() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let options = KvStoreOptions::default().durability(Durability::Every(Duration::from_secs(0)));
+    match KvStore::open_with(temp_dir.path(), options) {
+        Err(KvError::InvalidOption(_)) => {}
+        other => panic!("expected an option error, got {:?}", other.map(|_| ())),
+    }
+    assert!("every:0".parse::<Durability>().is_err());
+    assert_eq!(
+        "every:10".parse::<Durability>(),
+        Ok(Durability::Every(Duration::from_millis(10)))
+    );
+
+    Ok(())
+}
+
+// Files that were rotated and merged without a sync are
+// synced by the next flush
+#[test]
+fn flush_covers_rotated_and_merged_files() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let options = KvStoreOptions::default()
+        .durability(Durability::Never)
+        .segment_max_entries(2)
+        .compaction_trigger(2);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..20 {
+        store.set(format!("key{}", i % 3), format!("value{}", i))?;
+    }
+    store.wait_for_compaction();
+    store.flush()?;
+    store.set("key0".to_owned(), "last".to_owned())?;
+    store.flush()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("last".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value19".to_owned()));
+
+    Ok(())
+}
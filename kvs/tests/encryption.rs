@@ -0,0 +1,138 @@
+use kvs::engine::KvError;
+use kvs::{EncryptionKey, KvStore, KvStoreOptions, KvsEngine, Result};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open(dir: &Path, key: &EncryptionKey, old: Vec<EncryptionKey>) -> Result<KvStore> {
+    let options = KvStoreOptions::default()
+        .encryption_key(key.clone())
+        .old_encryption_keys(old);
+    KvStore::open_with(dir, options)
+}
+
+fn read_meta(dir: &Path) -> Value {
+    serde_json::from_str(&fs::read_to_string(dir.join("META")).unwrap()).unwrap()
+}
+
+fn write_meta(dir: &Path, meta: &Value) {
+    fs::write(dir.join("META"), meta.to_string()).unwrap();
+}
+
+fn assert_wrong_key(result: Result<KvStore>) {
+    match result {
+        Err(KvError::WrongKey(_)) => {}
+        other => panic!("expected a key error, got {:?}", other.map(|_| ())),
+    }
+}
+
+// Writes a key encrypted with the first key and returns the
+// metadata along with the check of the second key
+fn encrypted_with(dir: &Path, first: &EncryptionKey, second: &EncryptionKey) -> (Value, Value) {
+    {
+        let store = open(dir, first, vec![]).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+    let other = TempDir::new().unwrap();
+    open(other.path(), second, vec![]).unwrap();
+    (read_meta(dir), read_meta(other.path())["key_check"].clone())
+}
+
+// Until all files are rewritten with the new key, the old
+// one is still needed, even if we crashed in the middle
+#[test]
+fn interrupted_rotation_needs_old_key() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (old, new) = (EncryptionKey::generate(), EncryptionKey::generate());
+    let (mut meta, new_check) = encrypted_with(temp_dir.path(), &old, &new);
+    // what the rotation leaves behind before the rewrite is done
+    meta["old_key_checks"] = Value::Array(vec![meta["key_check"].clone()]);
+    meta["key_check"] = new_check;
+    write_meta(temp_dir.path(), &meta);
+
+    assert_wrong_key(open(temp_dir.path(), &new, vec![]));
+
+    {
+        let store = open(temp_dir.path(), &new, vec![old])?;
+        store.wait_for_compaction();
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    assert_eq!(read_meta(temp_dir.path()).get("old_key_checks"), None);
+
+    let store = open(temp_dir.path(), &new, vec![])?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Records under a key we don't have are not corrupted, so
+// they must be reported as a key problem
+#[test]
+fn record_under_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (old, new) = (EncryptionKey::generate(), EncryptionKey::generate());
+    let (mut meta, new_check) = encrypted_with(temp_dir.path(), &old, &new);
+    meta["key_check"] = new_check;
+    write_meta(temp_dir.path(), &meta);
+
+    assert_wrong_key(open(temp_dir.path(), &new, vec![]));
+
+    Ok(())
+}
+
+// Hints are written for encrypted stores as well, but the
+// keys in them must not be readable
+#[test]
+fn hints_are_encrypted() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let key = EncryptionKey::generate();
+    {
+        let options = KvStoreOptions::default()
+            .encryption_key(key.clone())
+            .segment_max_entries(2);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for i in 0..4 {
+            store.set(format!("secret{}", i), "value".to_owned())?;
+        }
+    }
+    let hints = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "hint"))
+        .collect::<Vec<_>>();
+    assert!(!hints.is_empty());
+    for path in hints {
+        let hint = fs::read(path)?;
+        assert!(!hint.windows(6).any(|window| window == b"secret"));
+    }
+
+    let store = open(temp_dir.path(), &key, vec![])?;
+    for i in 0..4 {
+        assert_eq!(store.get(format!("secret{}", i))?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A different key or none at all is refused when opening,
+// before anything is read or written with it
+#[test]
+fn wrong_key_is_refused() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (key, wrong) = (EncryptionKey::generate(), EncryptionKey::generate());
+    open(temp_dir.path(), &key, vec![])?.set("key1".to_owned(), "value1".to_owned())?;
+    let meta = read_meta(temp_dir.path());
+
+    assert_wrong_key(open(temp_dir.path(), &wrong, vec![]));
+    assert_wrong_key(KvStore::open(temp_dir.path()));
+    let read_only = KvStoreOptions::default()
+        .encryption_key(wrong)
+        .read_only(true);
+    assert_wrong_key(KvStore::open_with(temp_dir.path(), read_only));
+    assert_eq!(read_meta(temp_dir.path()), meta);
+
+    let store = open(temp_dir.path(), &key, vec![])?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
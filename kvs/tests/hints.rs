@@ -0,0 +1,89 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+// two files of two entries each, which are never compacted,
+// and the active one
+fn write_files(dir: &Path) -> Result<()> {
+    let options = KvStoreOptions::default()
+        .segment_max_entries(2)
+        .compaction_trigger(100);
+    let store = KvStore::open_with(dir, options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    Ok(())
+}
+
+fn assert_values(store: &KvStore) -> Result<()> {
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+fn open_read_only(dir: &Path) -> Result<KvStore> {
+    KvStore::open_with(dir, KvStoreOptions::default().read_only(true))
+}
+
+// Without a hint the file is read as a whole, and a writer
+// writes the hint again
+#[test]
+fn missing_hint_is_rebuilt() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    write_files(temp_dir.path())?;
+    let hint = temp_dir.path().join("2.hint");
+    fs::remove_file(&hint)?;
+
+    assert_values(&open_read_only(temp_dir.path())?)?;
+    assert!(!hint.exists());
+
+    assert_values(&KvStore::open(temp_dir.path())?)?;
+    assert!(hint.exists());
+    assert_values(&open_read_only(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// A hint that fails its checksum must not be used at all
+#[test]
+fn corrupt_hint_is_not_used() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    write_files(temp_dir.path())?;
+    let hint = temp_dir.path().join("2.hint");
+    let mut bytes = fs::read(&hint)?;
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    fs::write(&hint, &bytes)?;
+
+    assert_values(&open_read_only(temp_dir.path())?)?;
+    assert_eq!(fs::read(&hint)?, bytes);
+
+    assert_values(&KvStore::open(temp_dir.path())?)?;
+    assert_ne!(fs::read(&hint)?, bytes);
+    assert_values(&open_read_only(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// A hint of a file that was changed since describes offsets
+// that are no longer right
+#[test]
+fn hint_of_another_length_is_not_used() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    write_files(temp_dir.path())?;
+    // the second file has a 'Remove' and is shorter
+    fs::copy(
+        temp_dir.path().join("1.hint"),
+        temp_dir.path().join("2.hint"),
+    )?;
+
+    assert_values(&open_read_only(temp_dir.path())?)?;
+
+    Ok(())
+}
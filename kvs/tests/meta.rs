@@ -0,0 +1,139 @@
+use assert_cmd::prelude::*;
+use kvs::engine::KvError;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, SledKvsEngine};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn format_version(dir: &Path) -> Option<u64> {
+    let meta = fs::read_to_string(dir.join("META")).ok()?;
+    serde_json::from_str::<serde_json::Value>(&meta).ok()?["format_version"].as_u64()
+}
+
+// A directory from before there was any metadata is read like
+// the first format and only marked as upgraded once written to
+#[test]
+fn directory_without_meta_is_upgraded() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    fs::remove_file(temp_dir.path().join("META"))?;
+
+    {
+        let options = KvStoreOptions::default().read_only(true);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    assert_eq!(format_version(temp_dir.path()), None);
+
+    let version = {
+        let other = TempDir::new()?;
+        KvStore::open(other.path())?;
+        format_version(other.path())
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(format_version(temp_dir.path()), version);
+
+    Ok(())
+}
+
+// The JSON of the first version can't be read. The directory
+// must not be claimed for a format it isn't in
+#[test]
+fn json_directory_is_not_claimed() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    fs::write(
+        temp_dir.path().join("db.active"),
+        r#"{"Set":{"key":"key1","value":"value1","version":0}}"#,
+    )?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::WrongFormat { found: 0, .. }) => {}
+        other => panic!("expected a format error, got {:?}", other.map(|_| ())),
+    }
+    assert!(!temp_dir.path().join("META").exists());
+
+    Ok(())
+}
+
+fn assert_wrong_engine<T>(result: Result<T>, expected: &str, found: &str) {
+    match result {
+        Err(KvError::WrongEngine {
+            expected: ref e,
+            found: ref f,
+        }) if e == expected && f == found => {}
+        Err(err) => panic!("expected an engine error, got {:?}", err),
+        Ok(_) => panic!("expected an engine error"),
+    }
+}
+
+// Neither engine opens the directory of the other one
+#[test]
+fn other_engine_is_refused() -> Result<()> {
+    let (kvs_dir, sled_dir) = (TempDir::new()?, TempDir::new()?);
+    KvStore::open(kvs_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    SledKvsEngine::open(sled_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_wrong_engine(SledKvsEngine::open(kvs_dir.path()), "sled", "kvs");
+    assert_wrong_engine(KvStore::open(sled_dir.path()), "kvs", "sled");
+
+    // without the metadata, the engine is told by the files
+    fs::remove_file(sled_dir.path().join("META"))?;
+    assert_wrong_engine(KvStore::open(sled_dir.path()), "kvs", "sled");
+    assert!(!sled_dir.path().join("META").exists());
+
+    let store = KvStore::open(kvs_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A format from a newer version of the engine can't be read,
+// not even read-only
+#[test]
+fn newer_format_is_refused() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    let path = temp_dir.path().join("META");
+    let mut meta: serde_json::Value = serde_json::from_slice(&fs::read(&path)?)?;
+    let current = meta["format_version"].as_u64().unwrap() as u32;
+    meta["format_version"] = (current + 1).into();
+    fs::write(&path, serde_json::to_vec(&meta)?)?;
+
+    for read_only in &[false, true] {
+        let options = KvStoreOptions::default().read_only(*read_only);
+        match KvStore::open_with(temp_dir.path(), options) {
+            Err(KvError::WrongFormat { expected, found })
+                if expected == current && found == current + 1 => {}
+            other => panic!("expected a format error, got {:?}", other.map(|_| ())),
+        }
+    }
+    assert_eq!(
+        format_version(temp_dir.path()),
+        Some(u64::from(current + 1))
+    );
+
+    Ok(())
+}
+
+// The server doesn't start on the directory of the other engine
+#[test]
+fn server_refuses_other_engine() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:0"])
+        .current_dir(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("engine 'kvs'"));
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use kvs::engine::KvError;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open_read_only(dir: &Path) -> Result<KvStore> {
+    KvStore::open_with(dir, KvStoreOptions::default().read_only(true))
+}
+
+fn files(dir: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        files.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    files.sort();
+    Ok(files)
+}
+
+// A checkpoint has neither an active file nor a lock file
+// and reading it must not add them
+#[test]
+fn checkpoint_is_opened_read_only() -> Result<()> {
+    let (temp_dir, backup) = (TempDir::new()?, TempDir::new()?);
+    let target = backup.path().join("checkpoint");
+    {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.checkpoint(&target)?;
+    }
+    let before = files(&target)?;
+
+    let store = open_read_only(&target)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvError::ReadOnly) => {}
+        other => panic!("expected a read only error, got {:?}", other),
+    }
+    drop(store);
+    assert_eq!(files(&target)?, before);
+
+    Ok(())
+}
+
+#[test]
+fn empty_directory_is_opened_read_only() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+    assert!(files(temp_dir.path())?.is_empty());
+
+    Ok(())
+}
+
+// Any number of readers, but not along with a writer
+#[test]
+fn readers_share_the_lock() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+
+    let first = open_read_only(temp_dir.path())?;
+    let second = open_read_only(temp_dir.path())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Locked(_)) => {}
+        other => panic!("expected a lock error, got {:?}", other.map(|_| ())),
+    }
+    drop((first, second));
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
@@ -0,0 +1,345 @@
+use kvs::engine::KvError;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, WriteBatch};
+use serde_json::{json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn active_len(dir: &Path) -> u64 {
+    fs::metadata(dir.join("db.active")).unwrap().len()
+}
+
+fn truncate_active(dir: &Path, len: u64) {
+    OpenOptions::new()
+        .write(true)
+        .open(dir.join("db.active"))
+        .unwrap()
+        .set_len(len)
+        .unwrap();
+}
+
+// writes two keys and returns the length of the active
+// file before and after the second one was written
+fn write_two_keys(dir: &Path) -> Result<(u64, u64)> {
+    let store = KvStore::open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let before = active_len(dir);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    Ok((before, active_len(dir)))
+}
+
+// Cut the last record at every possible byte and check that
+// the store opens with only the first key
+#[test]
+fn recover_from_torn_write() -> Result<()> {
+    let (before, after) = write_two_keys(TempDir::new()?.path())?;
+
+    for len in before + 1..after {
+        let temp_dir = TempDir::new()?;
+        write_two_keys(temp_dir.path())?;
+        truncate_active(temp_dir.path(), len);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(active_len(temp_dir.path()), before);
+    }
+
+    Ok(())
+}
+
+// Writes after recovery must not be mixed with the remains
+// of the torn record
+#[test]
+fn write_after_recovery() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (before, after) = write_two_keys(temp_dir.path())?;
+    truncate_active(temp_dir.path(), (before + after) / 2);
+
+    {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Garbage that is not a truncated record must not be
+// silently thrown away
+#[test]
+fn corrupted_record_is_not_recovered() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (_, after) = write_two_keys(temp_dir.path())?;
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("db.active"))?;
+    file.write_all(&[0; 64])?;
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert_eq!(active_len(temp_dir.path()), after + 64);
+
+    Ok(())
+}
+
+// A batch that was cut off anywhere must be discarded as
+// a whole, while the writes before it are kept
+#[test]
+fn torn_batch_is_discarded() -> Result<()> {
+    let write = |dir: &Path| -> Result<(u64, u64)> {
+        let store = KvStore::open(dir)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let before = active_len(dir);
+        let mut batch = WriteBatch::new();
+        batch.delete(b"key1".to_vec());
+        batch.put(b"key2".to_vec(), b"value2".to_vec());
+        batch.put(b"key3".to_vec(), b"value3".to_vec());
+        store.write_batch(batch)?;
+        Ok((before, active_len(dir)))
+    };
+    let (before, after) = write(TempDir::new()?.path())?;
+
+    for len in before + 1..after {
+        let temp_dir = TempDir::new()?;
+        write(temp_dir.path())?;
+        truncate_active(temp_dir.path(), len);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, None);
+    }
+
+    let temp_dir = TempDir::new()?;
+    write(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A broken length makes a record look like it runs past the
+// end of the file, but it must not be taken for a torn write
+#[test]
+fn corrupted_length_is_not_recovered() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let (_, after) = write_two_keys(temp_dir.path())?;
+    let path = temp_dir.path().join("db.active");
+    let mut bytes = fs::read(&path)?;
+    // the value length of the first record
+    bytes[19] = 0xff;
+    fs::write(&path, &bytes)?;
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert_eq!(active_len(temp_dir.path()), after);
+
+    Ok(())
+}
+
+// The first version wrote the commands as JSON. Its files are
+// not torn binary records and must be left alone
+#[test]
+fn json_log_is_not_recovered() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let log = concat!(
+        r#"{"Set":{"key":"key1","value":"value1","version":0}}"#,
+        r#"{"Remove":{"key":"key1"}}"#
+    );
+    fs::write(temp_dir.path().join("db.active"), log)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::WrongFormat { found: 0, .. }) => {}
+        other => panic!("expected a format error, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(fs::read_to_string(temp_dir.path().join("db.active"))?, log);
+
+    Ok(())
+}
+
+// the state of the store after the compaction in `compact`
+const VALUES: [(&str, &str); 4] = [
+    ("key1", "value3"),
+    ("key2", "value2"),
+    ("key3", "value3"),
+    ("key4", "value4"),
+];
+
+// writes four files, checkpoints them to `before` and compacts
+// them in `dir`. the first file is all stale and the second has
+// one current value, so both are merged into one that takes the
+// place of the second
+fn compact(dir: &Path, before: &Path) -> Result<()> {
+    let options = KvStoreOptions::default()
+        .segment_max_entries(2)
+        .compaction_trigger(1);
+    let store = KvStore::open_with(dir, options)?;
+    store.pause_compaction();
+    for &(key, value) in &[
+        ("key1", "value1"),
+        ("key2", "value1"),
+        ("key1", "value2"),
+        ("key2", "value2"),
+        ("key1", "value3"),
+        ("key3", "value3"),
+        ("key4", "value4"),
+    ] {
+        store.set(key.to_owned(), value.to_owned())?;
+    }
+    store.checkpoint(before)?;
+    store.resume_compaction();
+    store.wait_for_compaction();
+    Ok(())
+}
+
+// the checkpoint of the files before the compaction along
+// with the merged file, as a compaction leaves them after
+// it wrote the merged file
+fn interrupted_merge() -> Result<(TempDir, TempDir)> {
+    let (temp_dir, backup) = (TempDir::new()?, TempDir::new()?);
+    let before = backup.path().join("before");
+    compact(temp_dir.path(), &before)?;
+    fs::copy(temp_dir.path().join("2.immutable"), before.join("2.merged"))?;
+    Ok((temp_dir, backup))
+}
+
+fn set_pending_merge(dir: &Path, inputs: &[u64], outputs: &[u64]) -> Result<()> {
+    let path = dir.join("META");
+    let mut meta: Value = serde_json::from_slice(&fs::read(&path)?)?;
+    meta["merge"] = json!({ "inputs": inputs, "outputs": outputs });
+    fs::write(&path, serde_json::to_vec(&meta)?)?;
+    Ok(())
+}
+
+fn has_pending_merge(dir: &Path) -> Result<bool> {
+    let meta: Value = serde_json::from_slice(&fs::read(dir.join("META"))?)?;
+    Ok(!meta["merge"].is_null())
+}
+
+// the log files without hints, which may be written again
+fn log_files(dir: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !name.ends_with(".hint") && name != "META" && name != "LOCK" {
+            files.push(name);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn assert_values(store: &KvStore) -> Result<()> {
+    for &(key, value) in &VALUES {
+        assert_eq!(store.get(key.to_owned())?, Some(value.to_owned()));
+    }
+    Ok(())
+}
+
+// The merged file is complete once the compaction is recorded,
+// so opening the store finishes it
+#[test]
+fn interrupted_merge_is_finished() -> Result<()> {
+    let (temp_dir, backup) = interrupted_merge()?;
+    let before = backup.path().join("before");
+    set_pending_merge(&before, &[1, 2], &[2])?;
+
+    match KvStore::open_with(&before, KvStoreOptions::default().read_only(true)) {
+        Err(KvError::Consistency(_)) => {}
+        other => panic!("expected a consistency error, got {:?}", other.map(|_| ())),
+    }
+    assert!(before.join("1.immutable").exists());
+
+    let store = KvStore::open(&before)?;
+    assert_values(&store)?;
+    assert!(!has_pending_merge(&before)?);
+    assert_eq!(
+        log_files(&before)?,
+        ["2.immutable", "3.immutable", "4.immutable", "db.active"]
+    );
+    assert_eq!(
+        fs::read(before.join("2.immutable"))?,
+        fs::read(temp_dir.path().join("2.immutable"))?
+    );
+
+    Ok(())
+}
+
+// Installing the merged file can be cut off after any of its
+// steps and must be picked up from there
+#[test]
+fn merge_interrupted_while_installing() -> Result<()> {
+    // what is left of the inputs: the first file is removed,
+    // then the merged one is renamed over the second
+    for &(first_removed, renamed) in &[(true, false), (true, true)] {
+        let (temp_dir, backup) = interrupted_merge()?;
+        let before = backup.path().join("before");
+        set_pending_merge(&before, &[1, 2], &[2])?;
+        if first_removed {
+            fs::remove_file(before.join("1.hint"))?;
+            fs::remove_file(before.join("1.immutable"))?;
+        }
+        if renamed {
+            fs::remove_file(before.join("2.hint"))?;
+            fs::rename(before.join("2.merged"), before.join("2.immutable"))?;
+        }
+
+        let store = KvStore::open(&before)?;
+        assert_values(&store)?;
+        assert!(!has_pending_merge(&before)?);
+        assert_eq!(
+            log_files(&before)?,
+            ["2.immutable", "3.immutable", "4.immutable", "db.active"]
+        );
+        assert_eq!(
+            fs::read(before.join("2.immutable"))?,
+            fs::read(temp_dir.path().join("2.immutable"))?
+        );
+    }
+
+    Ok(())
+}
+
+// A compaction that was cut off before it was recorded leaves
+// files that are never read and removed by the next writer
+#[test]
+fn leftover_merge_files_are_removed() -> Result<()> {
+    let (_temp_dir, backup) = interrupted_merge()?;
+    let before = backup.path().join("before");
+    fs::write(before.join("3.retained"), b"leftover")?;
+    let leftovers = [
+        "1.immutable",
+        "2.immutable",
+        "2.merged",
+        "3.immutable",
+        "3.retained",
+        "4.immutable",
+    ];
+
+    {
+        let store = KvStore::open_with(&before, KvStoreOptions::default().read_only(true))?;
+        assert_values(&store)?;
+    }
+    assert_eq!(log_files(&before)?, leftovers);
+
+    let store = KvStore::open(&before)?;
+    assert_values(&store)?;
+    assert_eq!(
+        log_files(&before)?,
+        [
+            "1.immutable",
+            "2.immutable",
+            "3.immutable",
+            "4.immutable",
+            "db.active"
+        ]
+    );
+
+    Ok(())
+}
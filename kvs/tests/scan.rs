@@ -0,0 +1,165 @@
+use kvs::{KvStore, KvsEngine, Result, SledKvsEngine};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn pairs(keys: &[&[u8]]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    keys.iter()
+        .map(|key| (key.to_vec(), [b"value-", *key].concat()))
+        .collect()
+}
+
+fn set_all<E: KvsEngine>(engine: &E, keys: &[&[u8]]) -> Result<()> {
+    for (key, value) in pairs(keys) {
+        engine.set_bytes(key, value)?;
+    }
+    Ok(())
+}
+
+fn scan<E: KvsEngine>(scan: Result<E::Scan>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    scan?.collect()
+}
+
+// the start is part of the range, the end is not
+fn range_bounds<E: KvsEngine>(engine: E) -> Result<()> {
+    set_all(&engine, &[b"key0", b"key1", b"key2", b"key3", b"key4"])?;
+
+    let found = scan::<E>(engine.scan(b"key1".to_vec()..b"key3".to_vec(), None))?;
+    assert_eq!(found, pairs(&[b"key1", b"key2"]));
+
+    let found = scan::<E>(engine.scan(b"key3".to_vec().., None))?;
+    assert_eq!(found, pairs(&[b"key3", b"key4"]));
+
+    let found = scan::<E>(engine.scan(..b"key1".to_vec(), None))?;
+    assert_eq!(found, pairs(&[b"key0"]));
+
+    let found = scan::<E>(engine.scan(b"key2".to_vec()..b"key2".to_vec(), None))?;
+    assert!(found.is_empty());
+    Ok(())
+}
+
+fn limit<E: KvsEngine>(engine: E) -> Result<()> {
+    set_all(&engine, &[b"key0", b"key1", b"key2", b"key3"])?;
+
+    let found = scan::<E>(engine.scan(b"key1".to_vec().., Some(2)))?;
+    assert_eq!(found, pairs(&[b"key1", b"key2"]));
+
+    let found = scan::<E>(engine.scan_prefix(b"key".to_vec(), Some(10)))?;
+    assert_eq!(found.len(), 4);
+
+    let found = scan::<E>(engine.scan_prefix(b"key".to_vec(), Some(0)))?;
+    assert!(found.is_empty());
+    Ok(())
+}
+
+// the end of a prefix is found by counting up its last byte, which
+// does not work for 0xff
+fn prefix_with_ff<E: KvsEngine>(engine: E) -> Result<()> {
+    set_all(
+        &engine,
+        &[
+            b"a",
+            b"a\xfe\xff",
+            b"a\xff",
+            b"a\xff\x00",
+            b"a\xff\xff",
+            b"b",
+            b"\xff",
+            b"\xff\xff\x01",
+        ],
+    )?;
+
+    let found = scan::<E>(engine.scan_prefix(b"a\xff".to_vec(), None))?;
+    assert_eq!(found, pairs(&[b"a\xff", b"a\xff\x00", b"a\xff\xff"]));
+
+    let found = scan::<E>(engine.scan_prefix(b"\xff\xff".to_vec(), None))?;
+    assert_eq!(found, pairs(&[b"\xff\xff\x01"]));
+
+    let found = scan::<E>(engine.scan_prefix(Vec::new(), None))?;
+    assert_eq!(found.len(), 8);
+    Ok(())
+}
+
+fn removed_keys_are_skipped<E: KvsEngine>(engine: E) -> Result<()> {
+    set_all(&engine, &[b"key0", b"key1", b"key2", b"key3"])?;
+    engine.remove_bytes(b"key1".to_vec())?;
+    engine.remove_bytes(b"key3".to_vec())?;
+
+    let found = scan::<E>(engine.scan_prefix(b"key".to_vec(), None))?;
+    assert_eq!(found, pairs(&[b"key0", b"key2"]));
+
+    // the limit counts only the keys that are returned
+    let found = scan::<E>(engine.scan_prefix(b"key".to_vec(), Some(2)))?;
+    assert_eq!(found, pairs(&[b"key0", b"key2"]));
+    Ok(())
+}
+
+#[test]
+fn kvs_range_bounds() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    range_bounds(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_range_bounds() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    range_bounds(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn kvs_limit() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    limit(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_limit() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    limit(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn kvs_prefix_with_ff() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    prefix_with_ff(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_prefix_with_ff() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    prefix_with_ff(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn kvs_removed_keys_are_skipped() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    removed_keys_are_skipped(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_removed_keys_are_skipped() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    removed_keys_are_skipped(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// sled has no ttls, so this is only for kvs
+#[test]
+fn expired_keys_are_skipped() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(100);
+    set_all(&store, &[b"key0", b"key2"])?;
+    store.set_with_ttl(b"key1".to_vec(), b"value-key1".to_vec(), ttl)?;
+    store.set_with_ttl(b"key3".to_vec(), b"value-key3".to_vec(), ttl)?;
+
+    let found = scan::<KvStore>(store.scan_prefix(b"key".to_vec(), None))?;
+    assert_eq!(found.len(), 4);
+
+    thread::sleep(ttl * 2);
+    let found = scan::<KvStore>(store.scan_prefix(b"key".to_vec(), None))?;
+    assert_eq!(found, pairs(&[b"key0", b"key2"]));
+
+    let found = scan::<KvStore>(store.scan_prefix(b"key".to_vec(), Some(1)))?;
+    assert_eq!(found, pairs(&[b"key0"]));
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+const KEYS: usize = 10;
+
+fn key(i: usize) -> String {
+    format!("key{}", i)
+}
+
+fn retained_files(dir: &Path) -> Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        if entry?
+            .path()
+            .extension()
+            .map(|ext| ext == "retained")
+            .unwrap_or(false)
+        {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// A snapshot reads the values it was taken with, while they are
+// overwritten, removed and compacted away underneath it
+#[test]
+fn snapshot_is_stable_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    // every rotation starts a compaction
+    let options = KvStoreOptions::default()
+        .segment_max_entries(2)
+        .compaction_trigger(1);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..KEYS {
+        store.set(key(i), "old".to_owned())?;
+    }
+    store.wait_for_compaction();
+    let snapshot = store.snapshot();
+    let versions: Vec<_> = (0..KEYS)
+        .map(|i| snapshot.version(key(i).into_bytes()))
+        .collect();
+
+    for i in 0..KEYS {
+        if i % 2 == 0 {
+            store.remove(key(i))?;
+        } else {
+            store.set(key(i), "new".to_owned())?;
+        }
+    }
+    store.set("added".to_owned(), "new".to_owned())?;
+    store.wait_for_compaction();
+    // the files with the old values were compacted, but are
+    // kept for the snapshot
+    assert!(retained_files(temp_dir.path())? > 0);
+
+    for (i, version) in versions.iter().enumerate() {
+        assert_eq!(snapshot.get(key(i))?, Some("old".to_owned()));
+        assert_eq!(snapshot.version(key(i).into_bytes()), *version);
+    }
+    assert_eq!(snapshot.get("added".to_owned())?, None);
+    let scanned = snapshot.scan(.., None).collect::<Result<Vec<_>>>()?;
+    assert_eq!(scanned.len(), KEYS);
+    assert!(scanned.iter().all(|(_, value)| value == b"old"));
+
+    assert_eq!(store.get(key(0))?, None);
+    assert_eq!(store.get(key(1))?, Some("new".to_owned()));
+
+    // nothing needs the old files once the snapshot is gone
+    drop(snapshot);
+    assert_eq!(retained_files(temp_dir.path())?, 0);
+
+    Ok(())
+}
+
+// Writes after the snapshot are not seen, not even when they
+// are written while the snapshot is being read
+#[test]
+fn snapshot_ignores_later_writes() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let snapshot = store.snapshot();
+
+    let mut scan = snapshot.scan(.., None);
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        scan.next().transpose()?,
+        Some((b"key1".to_vec(), b"value1".to_vec()))
+    );
+    assert!(scan.next().is_none());
+    assert_eq!(snapshot.get("key2".to_owned())?, None);
+    assert!(snapshot.sequence() < store.snapshot().sequence());
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use kvs::{KvStore, KvsEngine, Result};
+use tempfile::TempDir;
+
+const KEYS: usize = 2000;
+
+fn key(i: usize) -> String {
+    format!("key{:05}", i)
+}
+
+// the keys take up a few chunks of the arena. once they are
+// removed, only the chunk new keys go into is left
+#[test]
+fn memory_follows_the_keys() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    let empty = store.stats().memory;
+    assert_eq!((empty.keys, empty.key_bytes, empty.index_bytes), (0, 0, 0));
+
+    for i in 0..KEYS {
+        store.set(key(i), "value".to_owned())?;
+    }
+    let full = store.stats().memory;
+    assert_eq!(full.keys, KEYS);
+    assert_eq!(full.key_bytes, KEYS * key(0).len());
+    assert!(full.arena_bytes >= full.key_bytes, "{:?}", full);
+    assert!(full.index_bytes > full.arena_bytes, "{:?}", full);
+
+    // overwriting doesn't add keys
+    for i in 0..KEYS {
+        store.set(key(i), "other".to_owned())?;
+    }
+    let overwritten = store.stats().memory;
+    assert_eq!(overwritten.keys, KEYS);
+    assert_eq!(overwritten.arena_bytes, full.arena_bytes);
+
+    for i in 0..KEYS / 2 {
+        store.remove(key(i))?;
+    }
+    let half = store.stats().memory;
+    assert_eq!(half.keys, KEYS / 2);
+    assert_eq!(half.key_bytes, full.key_bytes / 2);
+    assert!(half.index_bytes < full.index_bytes, "{:?}", half);
+
+    for i in KEYS / 2..KEYS {
+        store.remove(key(i))?;
+    }
+    let removed = store.stats().memory;
+    assert_eq!((removed.keys, removed.key_bytes), (0, 0));
+    assert!(removed.arena_bytes < full.arena_bytes, "{:?}", removed);
+    assert!(removed.index_bytes < half.index_bytes, "{:?}", removed);
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use kvs::engine::KvError;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+fn contains(dir: &Path, needle: &[u8]) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        let content = fs::read(entry?.path())?;
+        if content.windows(needle.len()).any(|window| window == needle) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn assert_missing(store: &KvStore, key: &str) -> Result<()> {
+    assert_eq!(store.get(key.to_owned())?, None);
+    match store.ttl(key.as_bytes().to_vec()) {
+        Err(KvError::KeyNotFound) => Ok(()),
+        other => panic!("expected the key to be gone, got {:?}", other),
+    }
+}
+
+// The time a key expires at is stored, so neither the ttl
+// nor the expiry start over when the store is opened again
+#[test]
+fn ttl_survives_reopen() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let short = Duration::from_millis(200);
+    {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set_with_ttl(b"short".to_vec(), b"value".to_vec(), short)?;
+        store.set_with_ttl(b"long".to_vec(), b"value".to_vec(), HOUR)?;
+        store.set_with_ttl(b"persisted".to_vec(), b"value".to_vec(), short)?;
+        store.persist(b"persisted".to_vec())?;
+    }
+
+    {
+        let store = KvStore::open(temp_dir.path())?;
+        let ttl = store.ttl(b"long".to_vec())?.unwrap();
+        assert!(ttl <= HOUR && ttl > HOUR - Duration::from_secs(60));
+        assert_eq!(store.ttl(b"persisted".to_vec())?, None);
+    }
+    thread::sleep(short * 2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_missing(&store, "short")?;
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("persisted".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A value with a ttl that is copied by a compaction keeps it,
+// and one that expired stays gone, even though an older file
+// that isn't compacted still has a value for the key
+#[test]
+fn ttl_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    // every rotation starts a compaction
+    let options = || {
+        KvStoreOptions::default()
+            .segment_max_entries(2)
+            .compaction_trigger(1)
+    };
+    let ttl = Duration::from_millis(200);
+    let expiring = "expiring value ".repeat(10);
+    {
+        let store = KvStore::open_with(temp_dir.path(), options())?;
+        // a file with too little stale data to be compacted
+        store.set("key".to_owned(), "old value".to_owned())?;
+        store.set("big".to_owned(), "x".repeat(1000))?;
+        store.wait_for_compaction();
+
+        store.set_with_ttl(b"key".to_vec(), expiring.clone().into_bytes(), ttl)?;
+        store.set_with_ttl(b"long".to_vec(), b"value".to_vec(), HOUR)?;
+        store.wait_for_compaction();
+        thread::sleep(ttl * 2);
+
+        // the file with the expired value is compacted
+        // and the value with the long ttl copied
+        store.set("other1".to_owned(), "value".to_owned())?;
+        store.set("other2".to_owned(), "value".to_owned())?;
+        store.wait_for_compaction();
+        assert!(!contains(temp_dir.path(), expiring.as_bytes())?);
+        assert!(contains(temp_dir.path(), b"old value")?);
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    assert_missing(&store, "key")?;
+    let left = store.ttl(b"long".to_vec())?.unwrap();
+    assert!(left <= HOUR && left > HOUR - Duration::from_secs(60));
+
+    Ok(())
+}